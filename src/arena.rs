@@ -1,11 +1,15 @@
+mod export;
+
 use crate::{
+    board::Board,
     chess_move::Move,
     edge::Edge,
     hashtable::HashTable,
     historized_board::HistorizedBoard,
     node::{GameState, Node},
     search_type::SearchType,
-    uci::PRETTY_PRINT,
+    types::pieces::Color,
+    uci::{PRETTY_PRINT, SHOW_WDL},
     value::SCALE,
 };
 use std::{
@@ -30,6 +34,11 @@ pub struct Arena {
 
     root_visits: i32,
     root_total_score: f32,
+    root_total_draw: f32,
+    /// How much worse than equal (in centipawns) the engine considers a draw for itself
+    contempt: i32,
+    /// Value of a draw for each side to move, shifted away from 0.5 by contempt
+    draw_score: [f32; 2],
 
     lru_head: ArenaIndex,
     lru_tail: ArenaIndex,
@@ -51,6 +60,9 @@ impl Arena {
             root: ArenaIndex::NONE,
             root_visits: 0,
             root_total_score: 0.,
+            root_total_draw: 0.,
+            contempt: 0,
+            draw_score: [0.5; 2],
             depth: 0,
             nodes: 0,
             lru_head: ArenaIndex::NONE,
@@ -81,10 +93,24 @@ impl Arena {
         self.hash_table.clear();
         self.root_visits = 0;
         self.root_total_score = 0.;
+        self.root_total_draw = 0.;
         self.depth = 0;
         self.nodes = 0;
     }
 
+    pub const fn contempt(&self) -> i32 {
+        self.contempt
+    }
+
+    /// Changes the contempt used by later searches. Cached values and the tree kept for reuse were scored with the
+    /// old draw score, so both are thrown away when it changes.
+    pub fn set_contempt(&mut self, contempt: i32) {
+        if contempt != self.contempt {
+            self.contempt = contempt;
+            self.reset();
+        }
+    }
+
     pub fn insert(&mut self, board: &HistorizedBoard, parent: Option<ArenaIndex>, edge_idx: usize) -> ArenaIndex {
         let idx = self.remove_lru_node();
         self[idx] = Node::new(board.game_state(), parent, edge_idx);
@@ -108,6 +134,31 @@ impl Arena {
         self.nodes
     }
 
+    /// Root of the most recent search along with the position that was searched
+    pub fn last_search(&self) -> Option<(ArenaIndex, &HistorizedBoard)> {
+        if self.root == ArenaIndex::NONE {
+            return None;
+        }
+        Some((self.root, self.previous_board.as_ref()?))
+    }
+
+    /// Expected score of the move the last search picked, for the side to move at the root
+    pub fn best_score(&self) -> f32 {
+        self.final_move_selection(self.root).map_or(0.5, Edge::q)
+    }
+
+    /// Visits of every root move in the last search, which is what the policy is trained to predict
+    pub fn root_distribution(&self) -> Vec<(Move, i32)> {
+        if self.root == ArenaIndex::NONE {
+            return Vec::new();
+        }
+        self[self.root].edges().iter().map(|e| (e.m(), e.visits())).collect()
+    }
+
+    pub const fn root_visits(&self) -> i32 {
+        self.root_visits
+    }
+
     pub const fn capacity(&self) -> usize {
         self.node_list.len()
     }
@@ -183,8 +234,14 @@ impl Arena {
         );
     }
 
-    fn evaluate(&self, ptr: ArenaIndex, board: &HistorizedBoard) -> f32 {
-        self[ptr].evaluate().unwrap_or_else(|| board.wdl())
+    /// Returns the expected score and draw chance of a node for its side to move. Draws the network expects are
+    /// valued the same as draws on the board, so contempt also steers away from drawish positions.
+    fn evaluate(&self, ptr: ArenaIndex, board: &mut HistorizedBoard) -> (f32, f32) {
+        let draw_score = self.draw_score[board.stm()];
+        self[ptr].evaluate(draw_score).unwrap_or_else(|| {
+            let (w, d, _) = board.wdl_probabilities();
+            (d.mul_add(draw_score, w), d)
+        })
     }
 
     // https://github.com/lightvector/KataGo/blob/master/docs/GraphSearch.md#doing-monte-carlo-graph-search-correctly
@@ -195,11 +252,11 @@ impl Arena {
         board: &mut HistorizedBoard,
         parent_visits: i32,
         parent_total_score: f32,
-    ) -> f32 {
+    ) -> (f32, f32) {
         self.move_to_front(ptr);
         let hash = board.hash();
         // Simulate
-        let u = if self[ptr].is_terminal() || parent_visits == 0 {
+        let (u, d) = if self[ptr].is_terminal() || parent_visits == 0 {
             self.hash_table
                 .probe(board.hash())
                 .unwrap_or_else(|| self.evaluate(ptr, board))
@@ -221,7 +278,7 @@ impl Arena {
                 child_ptr
             });
 
-            let u = self.playout(
+            let (u, d) = self.playout(
                 child_ptr,
                 board,
                 self[ptr].edges()[edge_idx].visits(),
@@ -229,15 +286,16 @@ impl Arena {
             );
 
             // Backpropagation
-            self[ptr].edges_mut()[edge_idx].update_stats(u);
+            self[ptr].edges_mut()[edge_idx].update_stats(u, d);
 
-            u
+            (u, d)
         };
         self.move_to_front(ptr);
-        self.hash_table.insert(hash, u);
+        self.hash_table.insert(hash, u, d);
 
         assert!((0.0..=1.0).contains(&u));
-        1. - u
+        // Draw chance is the same from either side's point of view
+        (1. - u, d)
     }
 
     // Section 3.4 https://project.dke.maastrichtuniversity.nl/games/files/phd/Chaslot_thesis.pdf
@@ -312,13 +370,25 @@ impl Arena {
     }
 
     pub fn print_uci(&self, nodes: u64, search_start: Instant, max_depth: u64, avg_depth: u64) {
-        let q = self.final_move_selection(self.root).unwrap().q();
+        let best = self.final_move_selection(self.root).unwrap();
+        let q = best.q();
+        let model = self
+            .previous_board
+            .as_ref()
+            .map_or_else(|| Board::default().win_rate_model(), |b| b.board().win_rate_model());
+        let wdl = if SHOW_WDL.load(Ordering::Relaxed) {
+            let stm = self.previous_board.as_ref().map_or(Color::White, |b| b.board().stm());
+            let [win, draw, loss] = uci_wdl(best, self.draw_score[stm]);
+            format!(" wdl {win} {draw} {loss}")
+        } else {
+            String::new()
+        };
         print!(
-            "info time {} depth {} seldepth {} score cp {} nodes {} nps {} hashfull {:.0} pv ",
+            "info time {} depth {} seldepth {} score cp {}{wdl} nodes {} nps {} hashfull {:.0} pv ",
             search_start.elapsed().as_millis(),
             avg_depth,
             max_depth,
-            (-SCALE * ((1. - q) / q).ln()) as i32,
+            model.normalize(model.cp(q)),
             nodes,
             (nodes as f64 / search_start.elapsed().as_secs_f64()) as i64,
             (self.capacity() as f64 - self.empty_slots() as f64) / self.capacity() as f64 * 1000.,
@@ -345,6 +415,11 @@ impl Arena {
     ) -> Move {
         let search_start = Instant::now();
 
+        // Contempt is relative to whichever side the engine is playing for this search
+        let engine_draw = 1.0 / (1.0 + (self.contempt as f32 / SCALE).exp());
+        self.draw_score[board.stm()] = engine_draw;
+        self.draw_score[!board.stm()] = 1. - engine_draw;
+
         if let Some(new_root) = self.reuse_tree(board) {
             if self[new_root].edges().is_empty() {
                 self.reset();
@@ -352,6 +427,7 @@ impl Arena {
             } else if new_root != self.root {
                 self.root_visits = self.parent_edge(new_root).map_or(0, Edge::visits);
                 self.root_total_score = self.parent_edge(new_root).map_or(0.0, Edge::total_score);
+                self.root_total_draw = self.parent_edge(new_root).map_or(0.0, Edge::total_draw);
                 self[new_root].make_root();
                 self.root = new_root;
             }
@@ -361,6 +437,12 @@ impl Arena {
         }
         let root = self.root;
         self[root].set_game_state(GameState::Ongoing);
+        self.previous_board = Some(board.clone());
+
+        // Every playout starts from a copy of this board, so getting its accumulator up to date now means
+        // evaluations only have to apply the changes made along the playout
+        let mut root_board = board.clone();
+        root_board.raw_eval();
 
         let mut total_depth = 0;
         let mut max_depth = 0;
@@ -369,9 +451,15 @@ impl Arena {
         loop {
             self.depth = 0;
 
-            let u = self.playout(self.root, &mut board.clone(), self.root_visits, self.root_total_score);
+            let (u, d) = self.playout(
+                self.root,
+                &mut root_board.clone(),
+                self.root_visits,
+                self.root_total_score,
+            );
             self.root_visits += 1;
             self.root_total_score += u;
+            self.root_total_draw += d;
 
             self.nodes += 1;
             max_depth = self.depth.max(max_depth);
@@ -399,8 +487,6 @@ impl Arena {
             self.display_stats();
         }
 
-        self.previous_board = Some(board.clone());
-
         self.final_move_selection(self.root).unwrap().m()
     }
 }
@@ -458,3 +544,98 @@ impl From<ArenaIndex> for usize {
         (value.0.get() ^ u32::MAX) as Self
     }
 }
+
+/// Win, draw and loss chances of a move in permille, as `UCI_ShowWDL` reports them. `draw_score` is what a draw
+/// is worth to the side making the move, so that the chances add up to the move's value the way the search saw it.
+fn uci_wdl(edge: &Edge, draw_score: f32) -> [i32; 3] {
+    let draw = (edge.draw() * 1000.).round() as i32;
+    let win = (edge.draw().mul_add(-draw_score, edge.q()).clamp(0., 1.) * 1000.).round() as i32;
+    [win, draw, (1000 - win - draw).max(0)]
+}
+
+#[cfg(test)]
+mod arena_tests {
+    use super::{uci_wdl, Arena};
+    use crate::{
+        chess_move::Move, historized_board::HistorizedBoard, node::GameState, search_type::SearchType, value::SCALE,
+    };
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn repetition_uses_draw_score() {
+        // White is a queen down, and Kg1 repeats the position after white's last Kg1
+        let mut board = HistorizedBoard::from("k7/8/8/8/8/3q4/8/7K w - - 0 1");
+        for m in ["h1g1", "d3d4", "g1h1", "d4d3"].repeat(2) {
+            let m = Move::from_san(m, board.board());
+            board.make_move(m);
+        }
+
+        let mut arena = Arena::new(1.);
+        arena.set_contempt(100);
+        arena.start_search(&board, &AtomicBool::new(false), SearchType::Nodes(500), false);
+
+        let engine_draw = 1. / (1. + (100. / SCALE).exp());
+        let edge = arena[arena.root]
+            .edges()
+            .iter()
+            .find(|e| e.m().to_string() == "h1g1")
+            .unwrap();
+        assert!(edge.visits() > 0);
+        assert_eq!(arena[edge.child().unwrap()].game_state(), GameState::Draw);
+        assert!((edge.q() - engine_draw).abs() < 1e-5, "{} vs {engine_draw}", edge.q());
+
+        // The tree was scored with the old draw score, so it can't be reused
+        arena.set_contempt(0);
+        assert!(arena.last_search().is_none());
+    }
+
+    #[test]
+    fn draw_chance_reaches_wdl() {
+        let mut board = HistorizedBoard::from("k7/8/8/8/8/3q4/8/7K w - - 0 1");
+        for m in ["h1g1", "d3d4", "g1h1", "d4d3"].repeat(2) {
+            let m = Move::from_san(m, board.board());
+            board.make_move(m);
+        }
+        let mut arena = Arena::new(1.);
+        arena.start_search(&board, &AtomicBool::new(false), SearchType::Nodes(500), false);
+
+        // Every playout through the repetition ends in a draw, the rest only have the network's draw chances
+        let root = &arena[arena.root];
+        let repetition = root.edges().iter().find(|e| e.m().to_string() == "h1g1").unwrap();
+        assert!((repetition.draw() - 1.).abs() < 1e-5);
+        assert_eq!(uci_wdl(repetition, 0.5)[1], 1000);
+
+        let others = root
+            .edges()
+            .iter()
+            .filter(|e| e.visits() > 0 && e.m() != repetition.m());
+        for edge in others {
+            assert!((0.0..1.).contains(&edge.draw()), "{}: {}", edge.m(), edge.draw());
+            assert_eq!(uci_wdl(edge, 0.5).iter().sum::<i32>(), 1000);
+        }
+        // The root's total also has the draw chance of its own evaluation on the first playout
+        let total_draw = root.edges().iter().map(super::Edge::total_draw).sum::<f32>();
+        assert!((-1e-2..=1.01).contains(&(arena.root_total_draw - total_draw)));
+    }
+
+    #[test]
+    fn wdl_matches_q_with_contempt() {
+        let mut board = HistorizedBoard::from("k7/8/8/8/8/3q4/8/7K w - - 0 1");
+        for m in ["h1g1", "d3d4", "g1h1", "d4d3"].repeat(2) {
+            let m = Move::from_san(m, board.board());
+            board.make_move(m);
+        }
+        let mut arena = Arena::new(1.);
+        arena.set_contempt(200);
+        arena.start_search(&board, &AtomicBool::new(false), SearchType::Nodes(500), false);
+
+        let draw_score = arena.draw_score[board.board().stm()];
+        assert!(draw_score < 0.5);
+        for edge in arena[arena.root].edges().iter().filter(|e| e.visits() > 0) {
+            let [win, draw, loss] = uci_wdl(edge, draw_score);
+            assert_eq!(win + draw + loss, 1000);
+            let q = (draw as f32).mul_add(draw_score, win as f32) / 1000.;
+            assert!((q - edge.q()).abs() < 2e-3, "{}: {q} vs {}", edge.m(), edge.q());
+        }
+    }
+}
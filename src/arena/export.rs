@@ -0,0 +1,145 @@
+use super::{Arena, ArenaIndex};
+use crate::node::GameState;
+use std::fmt::Write;
+
+/// Per node information shared by every export format
+struct ExportNode {
+    idx: ArenaIndex,
+    m: String,
+    visits: i32,
+    q: Option<f32>,
+    policy: f32,
+    game_state: GameState,
+}
+
+impl Arena {
+    /// Dumps the subtree under the root as a graphviz digraph. Children are only included while they are
+    /// at most `max_depth` plies from the root and have at least `min_visits` visits.
+    pub fn export_dot(&self, max_depth: usize, min_visits: i32) -> String {
+        let mut str = String::from("digraph tree {\n    node [shape=box, fontname=monospace];\n");
+        if let Some(root) = self.export_root() {
+            self.dot_worker(&root, 0, max_depth, min_visits, &mut str);
+        }
+        str += "}\n";
+        str
+    }
+
+    /// Dumps the subtree under the root as nested json objects, using the same limits as [`Arena::export_dot`]
+    pub fn export_json(&self, max_depth: usize, min_visits: i32) -> String {
+        let mut str = String::new();
+        if let Some(root) = self.export_root() {
+            self.json_worker(&root, 0, max_depth, min_visits, &mut str);
+        } else {
+            str += "null";
+        }
+        str.push('\n');
+        str
+    }
+
+    fn export_root(&self) -> Option<ExportNode> {
+        if self.root == ArenaIndex::NONE {
+            return None;
+        }
+        Some(ExportNode {
+            idx: self.root,
+            m: "root".to_string(),
+            visits: self.root_visits,
+            q: (self.root_visits > 0).then(|| self.root_total_score / self.root_visits as f32),
+            policy: 1.,
+            game_state: self[self.root].game_state(),
+        })
+    }
+
+    fn export_children(&self, ptr: ArenaIndex, depth: usize, max_depth: usize, min_visits: i32) -> Vec<ExportNode> {
+        if depth >= max_depth {
+            return Vec::new();
+        }
+        self[ptr]
+            .edges()
+            .iter()
+            .filter(|edge| edge.visits() >= min_visits)
+            .filter_map(|edge| {
+                let child = edge.child()?;
+                Some(ExportNode {
+                    idx: child,
+                    m: edge.m().to_string(),
+                    visits: edge.visits(),
+                    q: (edge.visits() > 0).then(|| edge.q()),
+                    policy: edge.policy(),
+                    game_state: self[child].game_state(),
+                })
+            })
+            .collect()
+    }
+
+    fn dot_worker(&self, node: &ExportNode, depth: usize, max_depth: usize, min_visits: i32, str: &mut String) {
+        let id = usize::from(node.idx);
+        let q = node.q.map_or_else(|| "-".to_string(), |q| format!("{q:.4}"));
+        writeln!(
+            str,
+            "    n{id} [label=\"{}\\nn: {}\\nQ: {q}\\nP: {:.4}\\n{:?}\"];",
+            node.m, node.visits, node.policy, node.game_state
+        )
+        .unwrap();
+
+        for child in self.export_children(node.idx, depth, max_depth, min_visits) {
+            writeln!(str, "    n{id} -> n{};", usize::from(child.idx)).unwrap();
+            self.dot_worker(&child, depth + 1, max_depth, min_visits, str);
+        }
+    }
+
+    fn json_worker(&self, node: &ExportNode, depth: usize, max_depth: usize, min_visits: i32, str: &mut String) {
+        let q = node.q.map_or_else(|| "null".to_string(), |q| q.to_string());
+        write!(
+            str,
+            "{{\"move\":\"{}\",\"visits\":{},\"q\":{q},\"policy\":{},\"state\":\"{:?}\",\"children\":[",
+            node.m, node.visits, node.policy, node.game_state
+        )
+        .unwrap();
+
+        for (i, child) in self
+            .export_children(node.idx, depth, max_depth, min_visits)
+            .iter()
+            .enumerate()
+        {
+            if i > 0 {
+                str.push(',');
+            }
+            self.json_worker(child, depth + 1, max_depth, min_visits, str);
+        }
+        str.push_str("]}");
+    }
+}
+
+#[cfg(test)]
+mod export_tests {
+    use crate::{arena::Arena, historized_board::HistorizedBoard, search_type::SearchType};
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn exports_searched_tree() {
+        let mut arena = Arena::new(1.);
+        assert_eq!(arena.export_json(3, 1), "null\n");
+
+        let board = HistorizedBoard::from("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+        arena.start_search(&board, &AtomicBool::new(false), SearchType::Nodes(300), false);
+        let visited = arena.root_distribution().iter().filter(|&&(_, n)| n >= 5).count();
+        assert!(visited > 0);
+
+        let dot = arena.export_dot(1, 5);
+        assert!(dot.starts_with("digraph tree {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains(&format!("root\\nn: {}\\n", arena.root_visits())));
+        assert_eq!(dot.matches(" -> ").count(), visited);
+        assert_eq!(dot.matches("[label=").count(), visited + 1);
+
+        let json = arena.export_json(1, 5);
+        assert!(json.starts_with(&format!("{{\"move\":\"root\",\"visits\":{},", arena.root_visits())));
+        assert_eq!(json.matches("\"move\"").count(), visited + 1);
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+        assert_eq!(json.matches('[').count(), json.matches(']').count());
+
+        // Nothing below the root is deep enough to make it into a depth 0 export
+        assert_eq!(arena.export_dot(0, 1).matches(" -> ").count(), 0);
+    }
+}
@@ -159,6 +159,47 @@ impl Board {
         self.square_under_attack(!self.stm, self.king_square(self.stm))
     }
 
+    /// Whether `m` puts the opponent in check, without making it. Looks at the king from the occupancy after the move,
+    /// which catches discovered checks through the vacated squares, including the pawn taken en passant, and checks
+    /// from the castled rook.
+    pub fn gives_check(&self, m: Move) -> bool {
+        let king_sq = self.king_square(!self.stm);
+        let moved = m.promotion().unwrap_or_else(|| m.piece_moving(self).name());
+        let mut vacated = m.from().bitboard();
+        let mut rook_to = Bitboard::EMPTY;
+        if m.is_castle() {
+            vacated |= m.castle_type().rook_from().bitboard();
+            rook_to = m.castle_type().rook_to().bitboard();
+        } else if m.is_en_passant() {
+            vacated |= match self.stm {
+                Color::White => m.to().shift(South),
+                Color::Black => m.to().shift(North),
+            }
+            .bitboard();
+        }
+        let occ = self.occupancies() & !vacated | m.to().bitboard() | rook_to;
+
+        // Our pieces of each type once the move is made
+        let after = |piece: PieceName| {
+            let mut bb = self.piece_color(self.stm, piece) & !vacated;
+            if piece == moved {
+                bb |= m.to().bitboard();
+            }
+            if piece == PieceName::Rook {
+                bb |= rook_to;
+            }
+            bb
+        };
+        let diags = after(PieceName::Bishop) | after(PieceName::Queen);
+        let orthos = after(PieceName::Rook) | after(PieceName::Queen);
+
+        !(pawn_attacks(king_sq, !self.stm) & after(PieceName::Pawn)
+            | knight_attacks(king_sq) & after(PieceName::Knight)
+            | bishop_attacks(king_sq, occ) & diags
+            | rook_attacks(king_sq, occ) & orthos)
+            .is_empty()
+    }
+
     pub(super) fn pinned_and_checkers(&self) -> (Bitboard, Bitboard) {
         let mut pinned = Bitboard::EMPTY;
         let attacker = !self.stm;
@@ -191,6 +232,30 @@ impl Board {
         (self.piece(PieceName::Rook) | self.piece(PieceName::Queen)) & self.color(side)
     }
 
+    /// Squares attacked by each of `attacker`'s piece types, indexed by [`PieceName`]. Their union is
+    /// [`Board::threats`].
+    pub fn threats_by_piece(&self, attacker: Color) -> [Bitboard; 6] {
+        let mut threats = [Bitboard::EMPTY; 6];
+        let occ = self.occupancies() ^ self.king_square(self.stm).bitboard();
+
+        threats[PieceName::Pawn] = pawn_set_attacks(self.piece_color(attacker, PieceName::Pawn), attacker);
+        for sq in self.piece_color(attacker, PieceName::Knight) {
+            threats[PieceName::Knight] |= knight_attacks(sq);
+        }
+        for sq in self.piece_color(attacker, PieceName::Bishop) {
+            threats[PieceName::Bishop] |= bishop_attacks(sq, occ);
+        }
+        for sq in self.piece_color(attacker, PieceName::Rook) {
+            threats[PieceName::Rook] |= rook_attacks(sq, occ);
+        }
+        for sq in self.piece_color(attacker, PieceName::Queen) {
+            threats[PieceName::Queen] |= bishop_attacks(sq, occ) | rook_attacks(sq, occ);
+        }
+        threats[PieceName::King] = king_attacks(self.king_square(attacker));
+
+        threats
+    }
+
     pub fn threats(&self, attacker: Color) -> Bitboard {
         let mut threats = Bitboard::EMPTY;
         let occ = self.occupancies() ^ self.king_square(self.stm).bitboard();
@@ -284,6 +349,27 @@ impl Board {
         self.zobrist_hash ^= ZOBRIST.turn;
     }
 
+    /// The same position with the colors of every piece swapped and the board mirrored top to bottom, so white's
+    /// pieces on the first rank become black's pieces on the eighth. The side to move, castling rights and en passant
+    /// square go along with them, so the position is identical from the side to move's point of view. There is no
+    /// fullmove number to carry over, since boards don't store one and always write 1 to FENs.
+    pub fn flip(&self) -> Self {
+        let mut board = Self::empty();
+        for sq in self.occupancies() {
+            let piece = self.piece_at(sq);
+            board.place_piece(Piece::new(piece.name(), !piece.color()), sq.flip_vertical());
+        }
+        board.stm = !self.stm;
+        // White's rights are the low two bits and black's the high two, see `Castle`
+        board.castling_rights = (self.castling_rights & 0b11) << 2 | self.castling_rights >> 2;
+        if self.can_en_passant() {
+            board.en_passant_square = self.en_passant_square.flip_vertical();
+        }
+        board.half_moves = self.half_moves;
+        board.zobrist_hash = board.generate_hash();
+        board
+    }
+
     /// Will have unexpected behavior if either of the arrays aren't completely disjoint sets with themselves
     pub fn from_bbs(piece_bbs: [Bitboard; 6], color_bbs: [Bitboard; 2], stm: Color) -> Self {
         let mut mailbox = [Piece::None; 64];
@@ -373,4 +459,126 @@ mod board_tests {
         c.remove_piece(Square(27));
         assert_eq!(board, c);
     }
+
+    /// Random walks from a handful of positions, which gives a few thousand positions with castling rights, en
+    /// passant squares and promotions in the mix
+    fn walk_positions() -> Vec<Board> {
+        let mut positions = Vec::new();
+        for fen in [
+            STARTING_FEN,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+            "2kr3r/ppp2ppp/2n1bn2/2bqp3/8/2NP1NP1/PPP1PPBP/R1BQ1RK1 b - - 0 9",
+        ] {
+            for offset in 0..6 {
+                let mut board = Board::from_fen(fen);
+                positions.push(board);
+                for ply in 0..80 {
+                    let moves = board.legal_moves();
+                    if moves.is_empty() {
+                        break;
+                    }
+                    board.make_move(moves[(ply * 7 + offset * 5) % moves.len()]);
+                    positions.push(board);
+                }
+            }
+        }
+        positions
+    }
+
+    fn flip_move(m: Move) -> Move {
+        Move::new(m.from().flip_vertical(), m.to().flip_vertical(), m.flag())
+    }
+
+    #[test]
+    fn flip_round_trips() {
+        let board = Board::from_fen("rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w Kq f6 0 3");
+        let flipped = board.flip();
+        assert_eq!(
+            flipped.to_fen(),
+            "rnbqkbnr/pppp1ppp/8/8/3PpP2/8/PPP1P1PP/RNBQKBNR b Qk f3 0 1"
+        );
+        assert_eq!(flipped, Board::from_fen(&flipped.to_fen()));
+
+        for board in walk_positions() {
+            let flipped = board.flip();
+            assert_eq!(flipped.flip(), board, "{board}");
+            // Hash and mailbox have to agree with a board built from scratch
+            assert_eq!(flipped, Board::from_fen(&flipped.to_fen()), "{board}");
+        }
+    }
+
+    /// Nothing seen from the side to move may change when the board is flipped
+    #[test]
+    fn flipping_is_symmetric() {
+        let positions = walk_positions();
+        assert!(positions.len() > 2000);
+        for board in positions {
+            let flipped = board.flip();
+            let fen = board.to_fen();
+
+            let moves = board.legal_moves();
+            let flipped_moves = flipped.legal_moves();
+            assert_eq!(moves.len(), flipped_moves.len(), "{fen}");
+            for &m in &moves {
+                assert!(flipped_moves.contains(&flip_move(m)), "{fen} {m}");
+                for threshold in [-300, -100, 0, 1, 100, 300] {
+                    assert_eq!(
+                        board.see(m, threshold),
+                        flipped.see(flip_move(m), threshold),
+                        "{fen} {m}"
+                    );
+                }
+            }
+
+            for color in [Color::White, Color::Black] {
+                assert_eq!(board.threats(color).flip_vertical(), flipped.threats(!color), "{fen}");
+                let by_piece = board.threats_by_piece(color).map(Bitboard::flip_vertical);
+                assert_eq!(by_piece, flipped.threats_by_piece(!color), "{fen}");
+            }
+
+            assert_eq!(board.raw_eval().to_bits(), flipped.raw_eval().to_bits(), "{fen}");
+
+            let flipped_policies = flipped.handcrafted_policies();
+            for (m, prior) in board.handcrafted_policies() {
+                let (_, flipped_prior) = flipped_policies.iter().find(|(f, _)| *f == flip_move(m)).unwrap();
+                assert!(
+                    (prior - flipped_prior).abs() < 1e-6,
+                    "{fen} {m}: {prior} vs {flipped_prior}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gives_check_matches_make_move() {
+        let mut positions = walk_positions();
+        for (fen, mv) in [
+            // Discovered checks by taking en passant, along the rank and the file
+            ("8/8/8/R2pP2k/8/8/8/4K3 w - d6 0 1", "e5d6"),
+            ("4k3/8/8/2KpP3/8/8/8/4R3 w - d6 0 1", "e5d6"),
+            // Checks from the castled rook
+            ("5k2/8/8/8/8/8/8/4K2R w K - 0 1", "e1g1"),
+            ("3k4/8/8/8/8/8/8/R3K3 w Q - 0 1", "e1c1"),
+            // A promoted queen checking, and a capture promotion uncovering a rook
+            ("2k5/P7/8/8/8/8/8/K7 w - - 0 1", "a7a8q"),
+            ("kn6/P7/8/8/8/8/8/R3K3 w - - 0 1", "a7b8n"),
+        ] {
+            let board = Board::from_fen(fen);
+            let m = board.legal_moves().into_iter().find(|m| m.to_string() == mv).unwrap();
+            assert!(board.gives_check(m), "{fen} {mv}");
+            positions.push(board);
+        }
+        for board in positions {
+            for m in board.legal_moves() {
+                let mut after = board;
+                after.make_move(m);
+                assert_eq!(board.gives_check(m), after.in_check(), "{} {m}", board.to_fen());
+            }
+        }
+    }
 }
@@ -0,0 +1,262 @@
+use crate::{
+    board::Board,
+    types::pieces::Color,
+    value::{normalize_material, sigmoid, WinRateModel, MAX_MATERIAL},
+};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    thread,
+};
+
+/// Evals are clamped to this many centipawns when building the histograms
+const CP_LIMIT: i32 = 2000;
+/// Material buckets with fewer positions than this are too noisy to fit
+const MIN_SAMPLES: u64 = 1000;
+const DEFAULT_OUTPUT: &str = "src/value/wdl_model.rs";
+
+/// Counts of [win, draw, loss] outcomes for the side to move, indexed by eval
+type Histogram = Vec<[u64; 3]>;
+
+/// Fits the win rate model in value.rs against a set of finished games.
+///
+/// The fitted coefficients are written to a rust file that replaces `src/value/wdl_model.rs`. Each line of the
+/// input is `fen | score | result`, with the result from white's point of view. The score column is ignored
+/// since we want to calibrate our own network's evals.
+///
+/// Usage: calibrate <data file> [output file]
+pub fn calibrate(args: &[String]) {
+    let Some(path) = args.first() else {
+        println!("Usage: calibrate <data file> [output file]");
+        return;
+    };
+    let output = args.get(1).map_or(DEFAULT_OUTPUT, String::as_str);
+
+    let lines = BufReader::new(File::open(path).expect("Data file not found"))
+        .lines()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>();
+    println!("Evaluating {} positions", lines.len());
+
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let chunk_size = lines.len().div_ceil(threads).max(1);
+    let mut histograms = empty_histograms();
+    thread::scope(|s| {
+        let mut handles = Vec::new();
+        for chunk in lines.chunks(chunk_size) {
+            handles.push(s.spawn(move || build_histograms(chunk)));
+        }
+
+        for handle in handles {
+            for (a, b) in histograms.iter_mut().zip(handle.join().unwrap()) {
+                for (x, y) in a.iter_mut().zip(b) {
+                    x[0] += y[0];
+                    x[1] += y[1];
+                    x[2] += y[2];
+                }
+            }
+        }
+    });
+
+    // (normalized material, a, b, number of samples)
+    let mut fits = Vec::new();
+    for (material, hist) in histograms.iter().enumerate() {
+        let samples = hist.iter().flatten().sum::<u64>();
+        if samples < MIN_SAMPLES {
+            continue;
+        }
+        let model = fit_bucket(hist);
+        println!(
+            "material {material:>2}: {samples:>9} positions, a = {:>8.2}, b = {:>8.2}",
+            model.a(),
+            model.b()
+        );
+        fits.push((
+            f64::from(normalize_material(material as i32)),
+            f64::from(model.a()),
+            f64::from(model.b()),
+            samples as f64,
+        ));
+    }
+    assert!(!fits.is_empty(), "Not enough data to calibrate the win rate model");
+
+    let a_coeffs = fit_cubic(&fits.iter().map(|&(m, a, _, n)| (m, a, n)).collect::<Vec<_>>());
+    let b_coeffs = fit_cubic(&fits.iter().map(|&(m, _, b, n)| (m, b, n)).collect::<Vec<_>>());
+    println!("AS: {a_coeffs:?}");
+    println!("BS: {b_coeffs:?}");
+
+    let str = format!(
+        "// Generated by `imm-cee-tee-ess calibrate`. Rerun the calibration instead of editing these by hand.\n\
+         pub const AS: [f32; 4] = {a_coeffs:?};\n\
+         pub const BS: [f32; 4] = {b_coeffs:?};\n"
+    );
+    fs::write(output, str).unwrap();
+    println!("Wrote model to {output}");
+}
+
+fn empty_histograms() -> Vec<Histogram> {
+    vec![vec![[0; 3]; (2 * CP_LIMIT + 1) as usize]; MAX_MATERIAL as usize + 1]
+}
+
+fn build_histograms(lines: &[String]) -> Vec<Histogram> {
+    let mut histograms = empty_histograms();
+    for line in lines {
+        let mut fields = line.split('|').map(str::trim);
+        let (Some(fen), Some(result)) = (fields.next(), fields.nth(1)) else {
+            continue;
+        };
+        let Ok(result) = result.trim_matches(['[', ']']).parse::<f32>() else {
+            continue;
+        };
+
+        let board = Board::from_fen(fen);
+        let result = if board.stm() == Color::White {
+            result
+        } else {
+            1. - result
+        };
+        let outcome = if result > 0.75 {
+            0
+        } else if result > 0.25 {
+            1
+        } else {
+            2
+        };
+
+        let cp = board.scaled_eval().clamp(-CP_LIMIT, CP_LIMIT);
+        let material = board.material().min(MAX_MATERIAL) as usize;
+        histograms[material][(cp + CP_LIMIT) as usize][outcome] += 1;
+    }
+    histograms
+}
+
+/// Average negative log likelihood of the outcomes in a histogram under a given model
+fn loss(hist: &Histogram, a: f64, b: f64) -> f64 {
+    let b = b.max(1.);
+    let mut total = 0.;
+    let mut count = 0;
+    for (i, counts) in hist.iter().enumerate() {
+        if counts.iter().all(|&c| c == 0) {
+            continue;
+        }
+        let cp = f64::from(i as i32 - CP_LIMIT);
+        let win = f64::from(sigmoid(((cp - a) / b) as f32)).max(1e-6);
+        let lose = f64::from(sigmoid(((-cp - a) / b) as f32)).max(1e-6);
+        let draw = (1. - win - lose).max(1e-6);
+        total -= (counts[0] as f64).mul_add(
+            win.ln(),
+            (counts[1] as f64).mul_add(draw.ln(), counts[2] as f64 * lose.ln()),
+        );
+        count += counts.iter().sum::<u64>();
+    }
+    total / count.max(1) as f64
+}
+
+/// Maximum likelihood fit of a and b for a single material bucket. Plain gradient descent with numerical
+/// gradients and a backtracking step is plenty for two parameters.
+fn fit_bucket(hist: &Histogram) -> WinRateModel {
+    let (mut a, mut b) = (100., 400.);
+    let mut step = 1000.;
+    let mut current = loss(hist, a, b);
+    for _ in 0..1000 {
+        let h = 0.5;
+        let grad_a = (loss(hist, a + h, b) - loss(hist, a - h, b)) / (2. * h);
+        let grad_b = (loss(hist, a, b + h) - loss(hist, a, b - h)) / (2. * h);
+
+        loop {
+            let (new_a, new_b) = (a - step * grad_a, (b - step * grad_b).max(1.));
+            let new = loss(hist, new_a, new_b);
+            if new < current {
+                (a, b, current) = (new_a, new_b, new);
+                step *= 1.5;
+                break;
+            }
+            step /= 2.;
+            if step < 1e-3 {
+                return WinRateModel::from_params(a as f32, b as f32);
+            }
+        }
+    }
+    WinRateModel::from_params(a as f32, b as f32)
+}
+
+/// Weighted least squares fit of a cubic through (x, y, weight) points. Coefficients are returned from the
+/// highest power down, matching how value.rs evaluates them.
+fn fit_cubic(points: &[(f64, f64, f64)]) -> [f32; 4] {
+    // Not enough points to pin down a cubic, so fall back on a flat weighted average
+    if points.len() < 4 {
+        let total = points.iter().map(|p| p.2).sum::<f64>();
+        let mean = points.iter().map(|p| p.1 * p.2).sum::<f64>() / total;
+        return [0., 0., 0., mean as f32];
+    }
+
+    // Normal equations, augmented with the right hand side
+    let mut m = [[0f64; 5]; 4];
+    for &(x, y, w) in points {
+        let powers = [x.powi(3), x.powi(2), x, 1.];
+        for r in 0..4 {
+            for c in 0..4 {
+                m[r][c] += w * powers[r] * powers[c];
+            }
+            m[r][4] += w * powers[r] * y;
+        }
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..4 {
+        let pivot = (col..4)
+            .max_by(|&i, &j| m[i][col].abs().partial_cmp(&m[j][col].abs()).unwrap())
+            .unwrap();
+        m.swap(col, pivot);
+        let pivot_row = m[col];
+        for (row, r) in m.iter_mut().enumerate() {
+            if row != col {
+                let factor = r[col] / pivot_row[col];
+                for (x, p) in r.iter_mut().zip(pivot_row).skip(col) {
+                    *x = (-factor).mul_add(p, *x);
+                }
+            }
+        }
+    }
+
+    [0, 1, 2, 3].map(|i| (m[i][4] / m[i][i]) as f32)
+}
+
+#[cfg(test)]
+mod calibrate_tests {
+    use super::{fit_bucket, fit_cubic, Histogram, CP_LIMIT};
+    use crate::value::WinRateModel;
+
+    #[test]
+    fn fit_cubic_recovers_polynomial() {
+        let coeffs = [3., -20., 150., 400.];
+        let points = (0..30)
+            .map(|i| {
+                let x = f64::from(i).mul_add(0.03, 0.3);
+                let y = coeffs.iter().fold(0., |acc: f64, &c| acc.mul_add(x, c));
+                (x, y, f64::from(1 + i % 4))
+            })
+            .collect::<Vec<_>>();
+        let fitted = fit_cubic(&points);
+        for (f, c) in fitted.iter().zip(coeffs) {
+            assert!((f64::from(*f) - c).abs() < 1e-2 * c.abs().max(1.), "{fitted:?}");
+        }
+
+        // Too few points for a cubic, so it's the weighted mean
+        let mean = fit_cubic(&[(0.5, 100., 1.), (0.7, 200., 3.)]);
+        for (f, c) in mean.iter().zip([0., 0., 0., 175.]) {
+            assert!((f - c).abs() < 1e-3, "{mean:?}");
+        }
+    }
+
+    #[test]
+    fn fit_bucket_recovers_model() {
+        let truth = WinRateModel::from_params(120., 250.);
+        let hist: Histogram = (-CP_LIMIT..=CP_LIMIT)
+            .map(|cp| <[f32; 3]>::from(truth.wdl(cp as f32)).map(|p| (f64::from(p) * 1000.).round() as u64))
+            .collect();
+        let fitted = fit_bucket(&hist);
+        assert!((fitted.a() - truth.a()).abs() < 3., "{fitted:?}");
+        assert!((fitted.b() - truth.b()).abs() < 5., "{fitted:?}");
+    }
+}
@@ -0,0 +1,511 @@
+use crate::{
+    arena::Arena,
+    board::{fen::STARTING_FEN, Board},
+    chess_move::Move,
+    historized_board::HistorizedBoard,
+    magics::Rng,
+    node::{GameState, Node},
+    search_type::SearchType,
+    types::{pieces::Color, square::Square},
+    value::SCALE,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    mem::size_of,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+/// Size of one position in bullet's `ChessBoard` format
+pub const RECORD_SIZE: usize = 32;
+
+const USAGE: &str =
+    "Usage: datagen <output file> [--games n] [--threads n] [--nodes n] [--seed n] [--book file] [--policy file]";
+
+/// Random moves played before the engine takes over. Alternating between an even and odd count means both
+/// colors get to move first out of the opening.
+const RANDOM_PLIES: [usize; 2] = [8, 9];
+/// Openings the network already thinks are lopsided are thrown out, in centipawns
+const MAX_OPENING_EVAL: f32 = 1000.;
+/// A game is adjudicated as won once both sides have agreed on a score this large for `WIN_PLIES` plies
+const WIN_SCORE: i32 = 2500;
+const WIN_PLIES: i32 = 4;
+/// A game is adjudicated as drawn once the score has stayed this close to zero for `DRAW_PLIES` plies, as long
+/// as it is past `DRAW_MIN_PLY`
+const DRAW_SCORE: i32 = 20;
+const DRAW_PLIES: usize = 12;
+const DRAW_MIN_PLY: usize = 80;
+const MAX_PLIES: usize = 600;
+
+#[derive(Clone, Debug)]
+struct Settings {
+    output: String,
+    games: usize,
+    threads: usize,
+    nodes: u64,
+    seed: u64,
+    book: Vec<String>,
+    /// Where root visit distributions are written for training the policy, if anywhere
+    policy: Option<String>,
+}
+
+/// Everything a game adds to the output files
+#[derive(Debug, PartialEq)]
+struct Game {
+    records: Vec<[u8; RECORD_SIZE]>,
+    /// Lines of policy data, see [`format_visits`]
+    visits: Vec<String>,
+    /// Result for white
+    result: f32,
+}
+
+/// Generates training data by playing the engine against itself at a fixed number of nodes per move.
+///
+/// Each game starts from a few random moves, played from the start position or from a line of the opening book.
+/// Positions are written in bullet's `ChessBoard` format along with the search score and game result. Game `i`
+/// only depends on the seed and `i`, and games are written in order, so a seed always produces the same file no
+/// matter how many threads play them. With `--policy`, the root visit distribution of every search is written to
+/// a second file as well.
+///
+/// Usage: datagen <output file> [--games n] [--threads n] [--nodes n] [--seed n] [--book file] [--policy file]
+pub fn datagen(args: &[String]) {
+    let settings = match parse_args(args) {
+        Ok(settings) => settings,
+        Err(err) => {
+            println!("{err}\n{USAGE}");
+            return;
+        }
+    };
+    println!(
+        "Playing {} games at {} nodes on {} threads, writing to {}",
+        settings.games, settings.nodes, settings.threads, settings.output
+    );
+
+    let writer = Mutex::new(GameWriter {
+        file: append(&settings.output),
+        policy_file: settings.policy.as_deref().map(append),
+        finished: BTreeMap::new(),
+        next: 0,
+        positions: 0,
+        distributions: 0,
+        results: [0; 3],
+    });
+    let next_game = AtomicUsize::new(0);
+    let start = Instant::now();
+
+    thread::scope(|s| {
+        for _ in 0..settings.threads {
+            s.spawn(|| {
+                let mut arena = Arena::new(tree_mb(settings.nodes));
+                loop {
+                    let game = next_game.fetch_add(1, Ordering::Relaxed);
+                    if game >= settings.games {
+                        break;
+                    }
+                    let played = play_game(&settings, game, &mut arena);
+                    writer.lock().unwrap().push(game, played, start);
+                }
+            });
+        }
+    });
+
+    let mut writer = writer.into_inner().unwrap();
+    writer.report(start);
+    writer.file.flush().unwrap();
+    println!("Wrote {} positions to {}", writer.positions, settings.output);
+    if let (Some(file), Some(path)) = (&mut writer.policy_file, &settings.policy) {
+        file.flush().unwrap();
+        println!("Wrote {} visit distributions to {path}", writer.distributions);
+    }
+}
+
+fn append(path: &str) -> BufWriter<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap_or_else(|err| panic!("Could not open {path}: {err}"));
+    BufWriter::new(file)
+}
+
+/// Every search starts from a tree that is cleared before each move, so it only needs room for the nodes of one
+/// search. Clearing a bigger tree would take longer than the search itself.
+fn tree_mb(nodes: u64) -> f32 {
+    (2 * nodes as usize * size_of::<Node>()) as f32 / (1024. * 1024.) + 1.
+}
+
+fn parse_args(args: &[String]) -> Result<Settings, String> {
+    let (output, flags) = args.split_first().ok_or("No output file given")?;
+    let mut settings = Settings {
+        output: output.clone(),
+        games: 1000,
+        threads: thread::available_parallelism().map_or(1, usize::from),
+        nodes: 5000,
+        seed: 0,
+        book: Vec::new(),
+        policy: None,
+    };
+    for pair in flags.chunks(2) {
+        let [key, value] = pair else {
+            return Err(format!("{} needs a value", pair[0]));
+        };
+        let number = || value.parse().map_err(|_| format!("Invalid value '{value}' for {key}"));
+        match key.as_str() {
+            "--games" => settings.games = number()?,
+            "--threads" => settings.threads = number()?.max(1),
+            "--nodes" => settings.nodes = number()? as u64,
+            "--seed" => settings.seed = number()? as u64,
+            "--book" => {
+                let text = fs::read_to_string(value).map_err(|err| format!("Could not read {value}: {err}"))?;
+                settings.book = text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            "--policy" => settings.policy = Some(value.clone()),
+            _ => return Err(format!("Unknown option {key}")),
+        }
+    }
+    Ok(settings)
+}
+
+/// Collects finished games, which can come in out of order, and writes them in the order they were started
+struct GameWriter {
+    file: BufWriter<File>,
+    policy_file: Option<BufWriter<File>>,
+    finished: BTreeMap<usize, Game>,
+    next: usize,
+    positions: usize,
+    distributions: usize,
+    /// White wins, draws, and black wins
+    results: [usize; 3],
+}
+
+impl GameWriter {
+    fn push(&mut self, idx: usize, game: Game, start: Instant) {
+        self.results[(2. * (1. - game.result)) as usize] += 1;
+        self.finished.insert(idx, game);
+        while let Some(game) = self.finished.remove(&self.next) {
+            for record in &game.records {
+                self.file.write_all(record).unwrap();
+            }
+            if let Some(file) = &mut self.policy_file {
+                for line in &game.visits {
+                    writeln!(file, "{line}").unwrap();
+                }
+                self.distributions += game.visits.len();
+            }
+            self.positions += game.records.len();
+            self.next += 1;
+            if self.next.is_multiple_of(100) {
+                self.file.flush().unwrap();
+                self.report(start);
+            }
+        }
+    }
+
+    fn report(&self, start: Instant) {
+        let [w, d, l] = self.results;
+        println!(
+            "{} games, {} positions, {:.0} positions/s, +{w} ={d} -{l}",
+            self.next,
+            self.positions,
+            self.positions as f64 / start.elapsed().as_secs_f64()
+        );
+    }
+}
+
+/// Plays one game from a random opening
+fn play_game(settings: &Settings, game: usize, arena: &mut Arena) -> Game {
+    let mut rng = Rng::with_seed(splitmix(splitmix(settings.seed) ^ game as u64));
+    let halt = AtomicBool::new(false);
+    let mut board = random_opening(settings, game, &mut rng);
+
+    // (position, search score for the side to move)
+    let mut positions = Vec::<(Board, i16)>::new();
+    let mut visits = Vec::new();
+    let mut ply = 0;
+    let mut win_plies = 0;
+    let mut draw_plies = 0;
+    let result = loop {
+        match board.game_state() {
+            GameState::Ongoing => (),
+            GameState::Lost => break if board.stm() == Color::White { 0. } else { 1. },
+            _ => break 0.5,
+        }
+        if ply >= MAX_PLIES {
+            break 0.5;
+        }
+
+        arena.reset();
+        let m = arena.start_search(&board, &halt, SearchType::Nodes(settings.nodes), false);
+        let score = score_to_cp(arena.best_score());
+        // Positions with a single legal move say nothing about the policy
+        if settings.policy.is_some() && board.legal_moves().len() > 1 {
+            visits.push(format_visits(board.board(), &arena.root_distribution()));
+        }
+
+        // Adjudicate once both sides agree the game is decided
+        let white_score = if board.stm() == Color::White { score } else { -score };
+        win_plies = win_streak(win_plies, white_score);
+        draw_plies = if i32::from(score).abs() <= DRAW_SCORE {
+            draw_plies + 1
+        } else {
+            0
+        };
+        if win_plies.abs() >= WIN_PLIES {
+            break if win_plies > 0 { 1. } else { 0. };
+        }
+        if draw_plies >= DRAW_PLIES && ply >= DRAW_MIN_PLY {
+            break 0.5;
+        }
+
+        if !skip_position(board.board(), m) {
+            positions.push((*board.board(), score));
+        }
+        board.make_move(m);
+        ply += 1;
+    };
+
+    let records = positions
+        .iter()
+        .map(|(pos, score)| {
+            let stm_result = if pos.stm() == Color::White { result } else { 1. - result };
+            to_bulletformat(pos, *score, stm_result)
+        })
+        .collect();
+    Game {
+        records,
+        visits,
+        result,
+    }
+}
+
+/// Neighbouring seeds would start xorshift in similar states, so they are scrambled first
+/// <https://prng.di.unimi.it/splitmix64.c>
+const fn splitmix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Positions in check or with a capture as the best move are noisy, since the eval of a quiet position can't
+/// capture what the search will resolve
+fn skip_position(board: &Board, best: Move) -> bool {
+    // Move flags don't tell quiet slider moves from captures, so look at the target square instead
+    board.in_check() || board.occupancies().occupied(best.to()) || best.is_en_passant()
+}
+
+/// Plays random legal moves until it finds an opening that isn't over and isn't already decided
+fn random_opening(settings: &Settings, game: usize, rng: &mut Rng) -> HistorizedBoard {
+    loop {
+        let fen = if settings.book.is_empty() {
+            STARTING_FEN
+        } else {
+            &settings.book[rng.next_u64() as usize % settings.book.len()]
+        };
+        let mut board = HistorizedBoard::from(fen);
+        for _ in 0..RANDOM_PLIES[game % 2] {
+            let moves = board.legal_moves();
+            if moves.is_empty() {
+                break;
+            }
+            board.make_move(moves[rng.next_u64() as usize % moves.len()]);
+        }
+        if board.game_state() == GameState::Ongoing && board.raw_eval().abs() <= MAX_OPENING_EVAL {
+            return board;
+        }
+    }
+}
+
+/// Extends the run of plies the winning score has lasted for, positive while white is winning and negative while
+/// black is. A score for the other side or below `WIN_SCORE` starts over.
+fn win_streak(streak: i32, white_score: i16) -> i32 {
+    let white_score = i32::from(white_score);
+    if white_score >= WIN_SCORE {
+        streak.max(0) + 1
+    } else if white_score <= -WIN_SCORE {
+        streak.min(0) - 1
+    } else {
+        0
+    }
+}
+
+/// Converts an expected score to centipawns on the same sigmoid the trainer fits evals to
+fn score_to_cp(score: f32) -> i16 {
+    let score = score.clamp(1e-6, 1. - 1e-6);
+    (SCALE * (score / (1. - score)).ln()).clamp(-f32::from(i16::MAX), f32::from(i16::MAX)) as i16
+}
+
+/// Formats a searched position as `fen | move:visits move:visits ...`, the text format policy data is kept in
+pub fn format_visits(board: &Board, visits: &[(Move, i32)]) -> String {
+    let moves = visits.iter().map(|(m, n)| format!("{m}:{n}")).collect::<Vec<_>>();
+    format!("{} | {}", board.to_fen(), moves.join(" "))
+}
+
+/// Parses a line written by [`format_visits`]. Moves that aren't legal in the position make the whole line invalid.
+pub fn parse_visits(line: &str) -> Option<(Board, Vec<(Move, i32)>)> {
+    let (fen, moves) = line.split_once('|')?;
+    let board = Board::from_fen(fen.trim());
+    let legal = board.legal_moves();
+    let visits = moves
+        .split_whitespace()
+        .map(|entry| {
+            let (m, n) = entry.split_once(':')?;
+            let m = legal.iter().find(|l| l.to_string() == m)?;
+            Some((*m, n.parse().ok()?))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((board, visits))
+}
+
+/// Encodes a position in bullet's `ChessBoard` format.
+///
+/// The board is seen from the side to move, which is always the first color and at the bottom of the board.
+/// `score` and `result` are for the side to move as well.
+pub fn to_bulletformat(board: &Board, score: i16, result: f32) -> [u8; RECORD_SIZE] {
+    let stm = board.stm();
+    let relative = |sq: Square| sq.relative_flip_vertical(stm);
+
+    let mut occ = 0u64;
+    for sq in board.occupancies() {
+        occ |= 1 << relative(sq).0;
+    }
+
+    // One nibble per piece, in the order of their squares. The top bit of a nibble marks the side not to move.
+    let mut pieces = [0u8; 16];
+    let mut remaining = occ;
+    let mut i = 0;
+    while remaining != 0 {
+        let sq = Square(remaining.trailing_zeros() as u8);
+        let piece = board.piece_at(relative(sq));
+        let nibble = piece.name() as u8 | (u8::from(piece.color() != stm) << 3);
+        pieces[i / 2] |= nibble << (4 * (i % 2));
+        i += 1;
+        remaining &= remaining - 1;
+    }
+
+    let mut record = [0; RECORD_SIZE];
+    record[0..8].copy_from_slice(&occ.to_le_bytes());
+    record[8..24].copy_from_slice(&pieces);
+    record[24..26].copy_from_slice(&score.to_le_bytes());
+    record[26] = (2. * result) as u8;
+    record[27] = relative(board.king_square(stm)).0;
+    // The opponent's king square is stored from their own point of view
+    record[28] = relative(board.king_square(!stm)).flip_vertical().0;
+    record
+}
+
+#[cfg(test)]
+mod datagen_tests {
+    use super::{
+        format_visits, parse_visits, play_game, score_to_cp, to_bulletformat, win_streak, Settings, WIN_PLIES,
+        WIN_SCORE,
+    };
+    use crate::{
+        arena::Arena,
+        board::Board,
+        types::{
+            pieces::{Color, PieceName},
+            square::Square,
+        },
+    };
+
+    #[test]
+    fn games_only_depend_on_seed() {
+        let settings = Settings {
+            output: String::new(),
+            games: 2,
+            threads: 1,
+            nodes: 16,
+            seed: 7,
+            book: Vec::new(),
+            policy: Some(String::new()),
+        };
+        let mut arena = Arena::new(1.);
+        let first = play_game(&settings, 1, &mut arena);
+        play_game(&settings, 0, &mut arena);
+        assert_eq!(first, play_game(&settings, 1, &mut arena));
+        assert_ne!(first, play_game(&Settings { seed: 8, ..settings }, 1, &mut arena));
+        assert!(!first.visits.is_empty());
+        for line in &first.visits {
+            let (board, visits) = parse_visits(line).unwrap();
+            assert_eq!(format_visits(&board, &visits), *line);
+        }
+        assert!(parse_visits("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 | e2e5:3").is_none());
+        let start = Board::default();
+        assert_eq!(
+            parse_visits(&format_visits(&start, &[])).map(|(b, v)| (b, v.len())),
+            Some((start, 0))
+        );
+    }
+
+    #[test]
+    fn scores_round_trip() {
+        assert_eq!(score_to_cp(0.5), 0);
+        assert!(score_to_cp(0.9) > 0 && (score_to_cp(0.1) + score_to_cp(0.9)).abs() <= 1);
+        assert!(score_to_cp(1.) > 5000 && score_to_cp(0.) < -5000);
+    }
+
+    #[test]
+    fn win_needs_agreeing_scores() {
+        let win = WIN_SCORE as i16;
+        let mut streak = 0;
+        for _ in 0..WIN_PLIES {
+            streak = win_streak(streak, win);
+        }
+        assert_eq!(streak, WIN_PLIES);
+
+        // Each side thinking it's winning is not agreement
+        streak = 0;
+        for ply in 0..2 * WIN_PLIES {
+            streak = win_streak(streak, if ply % 2 == 0 { win } else { -win });
+            assert!(streak.abs() < WIN_PLIES);
+        }
+        assert_eq!(win_streak(win_streak(0, -win), -win), -2);
+        assert_eq!(win_streak(3, win - 1), 0);
+    }
+
+    /// Black to move, so everything in the record is flipped to black's point of view
+    #[test]
+    fn bulletformat_is_stm_relative() {
+        let board = Board::from_fen("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 0 1");
+        let record = to_bulletformat(&board, -140, 1.);
+
+        let occ = u64::from_le_bytes(record[..8].try_into().unwrap());
+        assert_eq!(occ.swap_bytes(), board.occupancies().0);
+        assert_eq!(occ.count_ones(), 10);
+
+        let nibbles = record[8..24]
+            .iter()
+            .flat_map(|byte| [byte & 15, byte >> 4])
+            .take(10)
+            .collect::<Vec<_>>();
+        // The c7 pawn is closest to black's back rank, the white pawns on e2 and g2 are furthest from it
+        assert_eq!(nibbles[0], PieceName::Pawn as u8);
+        assert_eq!(nibbles[8..], [PieceName::Pawn as u8 | 8; 2]);
+        let mut remaining = occ;
+        for nibble in nibbles {
+            let sq = Square(remaining.trailing_zeros() as u8).flip_vertical();
+            let piece = board.piece_at(sq);
+            assert_eq!(nibble & 7, piece.name() as u8, "{sq:?}");
+            assert_eq!(nibble >> 3 == 1, piece.color() == Color::White, "{sq:?}");
+            remaining &= remaining - 1;
+        }
+
+        assert_eq!(i16::from_le_bytes([record[24], record[25]]), -140);
+        assert_eq!(record[26], 2);
+        // Black's king on h4 is h5 from black's side, white's king on a5 is kept from white's side
+        assert_eq!(record[27], 39);
+        assert_eq!(record[28], 32);
+    }
+}
@@ -6,6 +6,7 @@ pub struct Edge {
     visits: i32,
     child_ptr: Option<ArenaIndex>,
     total_score: f32,
+    total_draw: f32,
     policy: f32,
 }
 
@@ -16,6 +17,7 @@ impl Edge {
             child_ptr,
             visits: 0,
             total_score: 0.,
+            total_draw: 0.,
             policy,
         }
     }
@@ -28,9 +30,16 @@ impl Edge {
         self.total_score / self.visits as f32
     }
 
-    pub fn update_stats(&mut self, u: f32) {
+    /// Average chance of a draw over all visits of this edge
+    pub fn draw(&self) -> f32 {
+        assert_ne!(0, self.visits, "Can't estimate draw chance of an unvisited edge");
+        self.total_draw / self.visits as f32
+    }
+
+    pub fn update_stats(&mut self, u: f32, d: f32) {
         self.visits += 1;
         self.total_score += u;
+        self.total_draw += d;
     }
 
     pub const fn m(&self) -> Move {
@@ -45,6 +54,10 @@ impl Edge {
         self.total_score
     }
 
+    pub const fn total_draw(&self) -> f32 {
+        self.total_draw
+    }
+
     pub const fn child(&self) -> Option<ArenaIndex> {
         self.child_ptr
     }
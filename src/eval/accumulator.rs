@@ -0,0 +1,177 @@
+use super::{
+    features::{changed_pieces, Attacks, FeatureList, PerspectiveFeatures, ThreatMaps},
+    network::MAX_L1_SIZE,
+    util::i16_update,
+    weights,
+};
+use crate::{
+    board::Board,
+    types::{bitboard::Bitboard, pieces::Color},
+};
+use arrayvec::ArrayVec;
+
+/// Feature transformer output that can be brought up to date with a new position by only adding and subtracting
+/// the features that changed, instead of summing every active feature from scratch.
+///
+/// A piece's features only depend on its square and whether it's attacked, and by what, so they can only change
+/// on squares whose occupant changed or whose attackers changed. We keep the position the accumulator was last
+/// updated for along with its attacks, and only recompute the attacks and features the changes could touch.
+#[derive(Clone, Debug)]
+pub struct Accumulator {
+    /// Indexed by perspective, not by side to move. Only the first `l1_size` values are used.
+    vals: [[i16; MAX_L1_SIZE]; 2],
+    l1_size: usize,
+    /// Position the accumulator currently reflects and its attacks, `None` when it has to be built from scratch
+    last: Option<(Board, Attacks)>,
+    /// Generation of the network the accumulator was built with
+    generation: u32,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self {
+            vals: [[0; MAX_L1_SIZE]; 2],
+            l1_size: 0,
+            last: None,
+            generation: u32::MAX,
+        }
+    }
+}
+
+impl Accumulator {
+    /// Makes the accumulator reflect `board`, regardless of which position it was last updated for
+    pub fn update(&mut self, board: &Board) {
+        // Nothing carries over if the network changed since the last update
+        let weights = weights();
+        if self.generation != weights.generation {
+            self.l1_size = weights.quantized.ft.bias.len();
+            self.last = None;
+            self.generation = weights.generation;
+        }
+
+        // Both perspectives share the same attacks
+        let attacks = self.last.as_ref().map_or_else(
+            || Attacks::new(board),
+            |(old, attacks)| {
+                let mut attacks = *attacks;
+                attacks.update(old, board);
+                attacks
+            },
+        );
+        let threats = attacks.maps();
+        let ft = &weights.quantized.ft;
+        let layout = &weights.float.arch.inputs;
+
+        for color in Color::iter() {
+            let vals = &mut self.vals[color][..self.l1_size];
+            let new_ctx = PerspectiveFeatures::new(board, color, threats, layout);
+            let mut adds = FeatureList::new();
+            let mut subs = FeatureList::new();
+
+            let old_ctx = self.last.as_ref().map(|(old, old_attacks)| {
+                (
+                    old,
+                    old_attacks.maps(),
+                    PerspectiveFeatures::new(old, color, old_attacks.maps(), layout),
+                )
+            });
+            match old_ctx {
+                // When the king moves to another bucket or flips the mirroring every feature changes
+                Some((old, old_threats, old_ctx)) if old_ctx.king_transform() == new_ctx.king_transform() => {
+                    let dirty = changed_squares(old, old_threats, board, threats);
+                    for sq in dirty & old.occupancies() {
+                        old_ctx.push(old, sq, &mut subs);
+                    }
+                    for sq in dirty & board.occupancies() {
+                        new_ctx.push(board, sq, &mut adds);
+                    }
+                    // A square that kept its piece and its threat bits shows up on both sides, no need to touch it
+                    subs.sort_unstable();
+                    adds.sort_unstable();
+                    let (adds, subs) = diff(&subs, &adds);
+                    i16_update(ft, vals, &adds, &subs);
+                }
+                _ => {
+                    vals.copy_from_slice(&ft.bias);
+                    for sq in board.occupancies() {
+                        new_ctx.push(board, sq, &mut adds);
+                    }
+                    i16_update(ft, vals, &adds, &[]);
+                }
+            }
+        }
+
+        self.last = Some((*board, attacks));
+    }
+
+    /// Accumulators in [stm, nstm] order, which is what the rest of the network expects
+    pub fn get(&self, stm: Color) -> [&[i16]; 2] {
+        [&self.vals[stm][..self.l1_size], &self.vals[!stm][..self.l1_size]]
+    }
+}
+
+/// Squares whose features may differ between the two positions: those whose occupant changed, and those that
+/// gained or lost an attacker of some type
+fn changed_squares(old: &Board, old_threats: &ThreatMaps, new: &Board, new_threats: &ThreatMaps) -> Bitboard {
+    let mut dirty = changed_pieces(old, new);
+    for (a, b) in old_threats.iter().flatten().zip(new_threats.iter().flatten()) {
+        dirty |= *a ^ *b;
+    }
+    dirty
+}
+
+/// Splits two sorted feature lists into the features that need to be added and removed to go from `old` to `new`
+fn diff(old: &FeatureList, new: &FeatureList) -> (FeatureList, FeatureList) {
+    let mut adds = ArrayVec::new();
+    let mut subs = ArrayVec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() && j < new.len() {
+        match old[i].cmp(&new[j]) {
+            std::cmp::Ordering::Less => {
+                subs.push(old[i]);
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                adds.push(new[j]);
+                j += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    subs.extend(old[i..].iter().copied());
+    adds.extend(new[j..].iter().copied());
+
+    (adds, subs)
+}
+
+#[cfg(test)]
+mod accumulator_tests {
+    use crate::{board::Board, historized_board::HistorizedBoard};
+
+    #[test]
+    fn incremental_matches_refresh() {
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        ] {
+            let mut board = HistorizedBoard::from(fen);
+            for ply in 0..40 {
+                let moves = board.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                board.make_move(moves[(ply * 7) % moves.len()]);
+
+                let incremental = board.raw_eval();
+                let refreshed = Board::raw_eval(board.board());
+                assert_eq!(incremental.to_bits(), refreshed.to_bits(), "{}", board.board().to_fen());
+            }
+        }
+    }
+}
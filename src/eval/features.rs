@@ -0,0 +1,461 @@
+//! Turns a board into the indices of the network inputs that are active for it.
+//!
+//! This is the only place features are encoded. The trainer calls [`feature_pairs`] on its training positions, so
+//! anything changed here changes what networks are trained on as well.
+
+use crate::{
+    attack_boards::{king_attacks, knight_attacks, pawn_attacks},
+    board::Board,
+    magics::{bishop_attacks, rook_attacks},
+    types::{
+        bitboard::Bitboard,
+        pieces::{Color, Piece, PieceName},
+        square::Square,
+    },
+};
+use arrayvec::ArrayVec;
+use std::iter::Zip;
+
+/// Most input features that can be active for one perspective, with any set of inputs
+pub const MAX_ACTIVE: usize = ThreatInputs::AttackerTypes.max_active();
+/// Indices of the input features active for one perspective
+pub type FeatureList = ArrayVec<usize, MAX_ACTIVE>;
+/// Features of the side to move and of its opponent, paired up in the order trainers like bullet take them
+pub type FeaturePairs = Zip<arrayvec::IntoIter<usize, MAX_ACTIVE>, arrayvec::IntoIter<usize, MAX_ACTIVE>>;
+
+/// What the inputs say about the squares each piece is attacked from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreatInputs {
+    /// One input per piece and square, split four ways by whether the square is attacked by the perspective and
+    /// by its opponent
+    Attacked,
+    /// One input per piece and square, split by whether the piece is defended, plus an input for every type of
+    /// enemy piece attacking it. Lets the network see a pawn attacking a queen, rather than just an attacked queen.
+    AttackerTypes,
+}
+
+impl ThreatInputs {
+    /// Every set of inputs, in the order they are numbered in network files
+    pub const ALL: [Self; 2] = [Self::Attacked, Self::AttackerTypes];
+
+    /// Inputs for a single king bucket
+    pub const fn bucket_inputs(self) -> usize {
+        match self {
+            Self::Attacked => 768 * 4,
+            Self::AttackerTypes => 768 * 2 + 768 * 6,
+        }
+    }
+
+    /// Most inputs that can be active for a perspective at once
+    pub const fn max_active(self) -> usize {
+        match self {
+            Self::Attacked => 32,
+            Self::AttackerTypes => 32 * 7,
+        }
+    }
+}
+
+/// How piece placements are turned into input features
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputLayout {
+    /// Bucket for each square of the perspective's king, relative to that perspective. Every bucket gets its own
+    /// set of [`ThreatInputs::bucket_inputs`] inputs.
+    pub king_buckets: [u8; 64],
+    /// Mirror the board horizontally when the perspective's king is on the e-h files
+    pub mirrored: bool,
+    pub threats: ThreatInputs,
+}
+
+impl InputLayout {
+    pub const fn bucket_count(&self) -> usize {
+        let mut max = 0;
+        let mut i = 0;
+        while i < 64 {
+            if self.king_buckets[i] > max {
+                max = self.king_buckets[i];
+            }
+            i += 1;
+        }
+        max as usize + 1
+    }
+
+    pub const fn input_size(&self) -> usize {
+        self.threats.bucket_inputs() * self.bucket_count()
+    }
+
+    /// Offset of the king's bucket, and what squares are xored with for the perspective whose king is on `king`
+    fn king_transform(&self, king: Square, perspective: Color) -> (usize, u8) {
+        let mut flip = if perspective == Color::White { 0 } else { 56 };
+        if self.mirrored && king.file() >= 4 {
+            flip ^= 7;
+        }
+        let bucket = self.king_buckets[usize::from(king.0 ^ flip)];
+        (self.threats.bucket_inputs() * usize::from(bucket), flip)
+    }
+}
+
+/// Squares attacked by each piece type of each color, indexed by color and then by piece type
+pub type ThreatMaps = [[Bitboard; 6]; 2];
+
+pub fn threat_maps(board: &Board) -> ThreatMaps {
+    [
+        board.threats_by_piece(Color::White),
+        board.threats_by_piece(Color::Black),
+    ]
+}
+
+/// Attacks of the piece on every square along with the [`ThreatMaps`] they add up to, kept between positions so
+/// that after a move only the pieces it could have affected need their attacks recomputed
+#[derive(Clone, Copy, Debug)]
+pub struct Attacks {
+    by_square: [Bitboard; 64],
+    maps: ThreatMaps,
+}
+
+impl Attacks {
+    pub fn new(board: &Board) -> Self {
+        let occ = attack_occupancy(board);
+        let mut by_square = [Bitboard::EMPTY; 64];
+        for sq in board.occupancies() {
+            by_square[sq] = piece_attacks(board.piece_at(sq), sq, occ);
+        }
+        Self {
+            by_square,
+            maps: sum_attacks(board, &by_square),
+        }
+    }
+
+    pub const fn maps(&self) -> &ThreatMaps {
+        &self.maps
+    }
+
+    /// Brings the attacks of `old` up to date with `new`. Pieces that didn't move only see their attacks change
+    /// if they're sliders and the occupancy changed somewhere along what they used to attack, so only the pieces
+    /// on changed squares and those sliders are recomputed. Summing up the maps is then a few dozen ors.
+    pub fn update(&mut self, old: &Board, new: &Board) {
+        let moved = changed_pieces(old, new);
+        let occ = attack_occupancy(new);
+        let occ_changed = attack_occupancy(old) ^ occ;
+
+        for sq in moved {
+            self.by_square[sq] = if new.occupancies().contains(sq) {
+                piece_attacks(new.piece_at(sq), sq, occ)
+            } else {
+                Bitboard::EMPTY
+            };
+        }
+        let sliders = new.piece(PieceName::Bishop) | new.piece(PieceName::Rook) | new.piece(PieceName::Queen);
+        for sq in sliders & !moved {
+            if !(self.by_square[sq] & occ_changed).is_empty() {
+                self.by_square[sq] = piece_attacks(new.piece_at(sq), sq, occ);
+            }
+        }
+        self.maps = sum_attacks(new, &self.by_square);
+    }
+}
+
+/// Squares whose occupant differs between the two positions
+pub(super) fn changed_pieces(old: &Board, new: &Board) -> Bitboard {
+    let mut changed = old.color_bbs()[0] ^ new.color_bbs()[0];
+    for (a, b) in old.piece_bbs().into_iter().zip(new.piece_bbs()) {
+        changed |= a ^ b;
+    }
+    changed
+}
+
+/// Sliders see through the king of the side to move, the same way [`Board::threats_by_piece`] has them
+fn attack_occupancy(board: &Board) -> Bitboard {
+    board.occupancies() ^ board.king_square(board.stm()).bitboard()
+}
+
+fn piece_attacks(piece: Piece, sq: Square, occ: Bitboard) -> Bitboard {
+    match piece.name() {
+        PieceName::Pawn => pawn_attacks(sq, piece.color()),
+        PieceName::Knight => knight_attacks(sq),
+        PieceName::Bishop => bishop_attacks(sq, occ),
+        PieceName::Rook => rook_attacks(sq, occ),
+        PieceName::Queen => bishop_attacks(sq, occ) | rook_attacks(sq, occ),
+        PieceName::King => king_attacks(sq),
+        PieceName::None => Bitboard::EMPTY,
+    }
+}
+
+fn sum_attacks(board: &Board, by_square: &[Bitboard; 64]) -> ThreatMaps {
+    let mut maps = [[Bitboard::EMPTY; 6]; 2];
+    for (color, by_piece) in Color::iter().zip(&mut maps) {
+        for (pieces, map) in board.piece_bbs().into_iter().zip(by_piece) {
+            for sq in pieces & board.color(color) {
+                *map |= by_square[sq];
+            }
+        }
+    }
+    maps
+}
+
+/// Everything the features of a single piece depend on besides the piece itself and its square
+pub struct PerspectiveFeatures<'a> {
+    layout: &'a InputLayout,
+    threats: &'a ThreatMaps,
+    /// Union of each color's attacks
+    attacked: [Bitboard; 2],
+    perspective: Color,
+    bucket: usize,
+    flip: u8,
+}
+
+impl<'a> PerspectiveFeatures<'a> {
+    pub fn new(board: &Board, perspective: Color, threats: &'a ThreatMaps, layout: &'a InputLayout) -> Self {
+        let (bucket, flip) = layout.king_transform(board.king_square(perspective), perspective);
+        Self {
+            layout,
+            threats,
+            attacked: threats.map(|by_piece| by_piece.into_iter().fold(Bitboard::EMPTY, |acc, bb| acc | bb)),
+            perspective,
+            bucket,
+            flip,
+        }
+    }
+
+    /// King bucket offset and the xor applied to squares. When either changes, every feature does.
+    pub const fn king_transform(&self) -> (usize, u8) {
+        (self.bucket, self.flip)
+    }
+
+    /// Pushes the features of the piece on `sq`, which must be occupied
+    pub fn push(&self, board: &Board, sq: Square, feats: &mut FeatureList) {
+        let (bucket, perspective) = (self.bucket, self.perspective);
+        let piece = board.piece_at(sq);
+        let piece_sq = 384 * usize::from(piece.color() != perspective)
+            + 64 * usize::from(piece.name())
+            + usize::from(sq.0 ^ self.flip);
+        match self.layout.threats {
+            ThreatInputs::Attacked => feats.push(
+                bucket
+                    + 2 * 768 * usize::from(self.attacked[perspective].contains(sq))
+                    + 768 * usize::from(self.attacked[!perspective].contains(sq))
+                    + piece_sq,
+            ),
+            ThreatInputs::AttackerTypes => {
+                feats.push(bucket + 768 * usize::from(self.attacked[piece.color()].contains(sq)) + piece_sq);
+                for (attacker, attacks) in self.threats[!piece.color()].iter().enumerate() {
+                    if attacks.contains(sq) {
+                        feats.push(bucket + 768 * (2 + attacker) + piece_sq);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the features active from `perspective`'s point of view, sorted so that two lists can be diffed.
+pub fn features(board: &Board, perspective: Color, threats: &ThreatMaps, layout: &InputLayout) -> FeatureList {
+    let ctx = PerspectiveFeatures::new(board, perspective, threats, layout);
+    let mut feats = FeatureList::new();
+    for sq in board.occupancies() {
+        ctx.push(board, sq, &mut feats);
+    }
+    feats.sort_unstable();
+    feats
+}
+
+/// Features of both perspectives, indexed by color
+pub fn board_features(board: &Board, layout: &InputLayout) -> [FeatureList; 2] {
+    let threats = threat_maps(board);
+    [
+        features(board, Color::White, &threats, layout),
+        features(board, Color::Black, &threats, layout),
+    ]
+}
+
+/// Features of the side to move zipped with those of its opponent. Both perspectives always have the same number
+/// of active features.
+pub fn feature_pairs(board: &Board, layout: &InputLayout) -> FeaturePairs {
+    let [white, black] = board_features(board, layout);
+    let [stm, nstm] = if board.stm() == Color::White {
+        [white, black]
+    } else {
+        [black, white]
+    };
+    stm.into_iter().zip(nstm)
+}
+
+#[cfg(test)]
+mod features_tests {
+    use super::{board_features, feature_pairs, threat_maps, Attacks, InputLayout, ThreatInputs};
+    use crate::{
+        board::Board,
+        eval::{FOUR_BUCKETS, INPUT_LAYOUT},
+        types::{
+            bitboard::Bitboard,
+            pieces::{Color, PieceName},
+        },
+    };
+
+    const LAYOUT: InputLayout = InputLayout {
+        king_buckets: FOUR_BUCKETS,
+        mirrored: true,
+        threats: ThreatInputs::Attacked,
+    };
+    const ATTACKER_LAYOUT: InputLayout = InputLayout {
+        threats: ThreatInputs::AttackerTypes,
+        ..LAYOUT
+    };
+
+    fn all_features(board: &Board, layout: &InputLayout) -> [Vec<usize>; 2] {
+        board_features(board, layout).map(|f| f.to_vec())
+    }
+
+    /// Flips the board from left to right. Castling rights and en passant don't survive the flip.
+    fn mirror_fen(fen: &str) -> String {
+        let mut fields = fen.split_whitespace();
+        let ranks = fields
+            .next()
+            .unwrap()
+            .split('/')
+            .map(|r| r.chars().rev().collect::<String>());
+        format!("{} {} - -", ranks.collect::<Vec<_>>().join("/"), fields.next().unwrap())
+    }
+
+    #[test]
+    fn mirrored_boards_share_features() {
+        for fen in [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w - - 0 1",
+            "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "6k1/5ppp/8/8/8/8/PPP5/1K6 b - - 0 1",
+        ] {
+            let board = Board::from_fen(fen);
+            let mirrored = Board::from_fen(&mirror_fen(fen));
+            for layout in [LAYOUT, ATTACKER_LAYOUT] {
+                assert_eq!(all_features(&board, &layout), all_features(&mirrored, &layout), "{fen}");
+            }
+        }
+    }
+
+    #[test]
+    fn king_buckets_are_relative() {
+        // Kings on their own back rank land in the same bucket from both sides
+        let bucket_inputs = LAYOUT.threats.bucket_inputs();
+        let board = Board::from_fen("3k4/8/8/8/8/8/8/4K3 w - - 0 1");
+        let [white, black] = all_features(&board, &LAYOUT);
+        assert!(white.iter().chain(&black).all(|&f| f / bucket_inputs == 1));
+
+        let board = Board::from_fen("8/8/8/3k4/8/8/8/6K1 w - - 0 1");
+        let [white, black] = all_features(&board, &LAYOUT);
+        assert!(white.iter().all(|&f| f / bucket_inputs == 0));
+        assert!(black.iter().all(|&f| f / bucket_inputs == 3));
+    }
+
+    #[test]
+    fn threat_maps_match_threats() {
+        for fen in [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 0 1",
+        ] {
+            let board = Board::from_fen(fen);
+            for (color, by_piece) in Color::iter().zip(threat_maps(&board)) {
+                let union = by_piece.into_iter().fold(Bitboard::EMPTY, |acc, bb| acc | bb);
+                assert_eq!(union, board.threats(color), "{fen}");
+            }
+        }
+    }
+
+    /// Updating from a position a few moves back, the way a lagging accumulator does, gives the same maps as
+    /// computing them from scratch
+    #[test]
+    fn incremental_attacks_match() {
+        for fen in [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        ] {
+            let mut board = Board::from_fen(fen);
+            let mut last = board;
+            let mut attacks = Attacks::new(&board);
+            for ply in 0..60 {
+                let moves = board.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                board.make_move(moves[(ply * 11) % moves.len()]);
+                if ply % 3 != 0 {
+                    continue;
+                }
+                attacks.update(&last, &board);
+                assert_eq!(*attacks.maps(), threat_maps(&board), "{}", board.to_fen());
+                last = board;
+            }
+        }
+    }
+
+    #[test]
+    fn attacker_types() {
+        // Pawn on e4 attacks the queen on d5, which is defended by its king and attacked by nothing else
+        let board = Board::from_fen("8/8/3k4/3q4/4P3/8/8/4K3 w - - 0 1");
+        let [white, _] = all_features(&board, &ATTACKER_LAYOUT);
+        // e1 is mirrored to d1, so the board is flipped for white and d5 becomes e5
+        let queen = 384 + 64 * PieceName::Queen as usize + 36;
+        let bucket = ATTACKER_LAYOUT.threats.bucket_inputs();
+        assert!(white.contains(&(bucket + 768 + queen)));
+        assert!(white.contains(&(bucket + 768 * (2 + PieceName::Pawn as usize) + queen)));
+        assert_eq!(white.iter().filter(|&&f| (f - bucket) % 768 == queen).count(), 2);
+        // Four pieces, and the pawn is attacked by the queen
+        assert_eq!(white.len(), 6);
+    }
+
+    /// Swaps the colors of every piece and flips the board vertically, which is how bulletformat stores positions
+    /// with black to move. Castling rights and en passant don't survive the flip.
+    fn flip_fen(fen: &str) -> String {
+        let mut fields = fen.split_whitespace();
+        let ranks = fields.next().unwrap().split('/').rev().map(|rank| {
+            rank.chars()
+                .map(|c| {
+                    if c.is_uppercase() {
+                        c.to_ascii_lowercase()
+                    } else {
+                        c.to_ascii_uppercase()
+                    }
+                })
+                .collect::<String>()
+        });
+        let stm = if fields.next() == Some("w") { "b" } else { "w" };
+        format!("{} {stm} - -", ranks.collect::<Vec<_>>().join("/"))
+    }
+
+    /// The trainer sees every position from the side to move, so flipping a position must not change its features
+    #[test]
+    fn stm_relative_boards_share_features() {
+        let mut count = 0;
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "4k3/8/8/8/8/8/8/4K2R w K - 0 1",
+            "2kr3r/ppp2ppp/2n1bn2/2bqp3/8/2NP1NP1/PPP1PPBP/R1BQ1RK1 b - - 0 9",
+        ] {
+            for offset in 0..5 {
+                let mut board = Board::from_fen(fen);
+                for ply in 0..60 {
+                    let moves = board.legal_moves();
+                    if moves.is_empty() {
+                        break;
+                    }
+                    board.make_move(moves[(ply * 7 + offset * 3) % moves.len()]);
+
+                    let flipped = Board::from_fen(&flip_fen(&board.to_fen()));
+                    for layout in [INPUT_LAYOUT, LAYOUT, ATTACKER_LAYOUT] {
+                        let pairs = feature_pairs(&board, &layout).collect::<Vec<_>>();
+                        let flipped_pairs = feature_pairs(&flipped, &layout).collect::<Vec<_>>();
+                        assert_eq!(pairs, flipped_pairs, "{}", board.to_fen());
+                    }
+                    count += 1;
+                }
+            }
+        }
+        assert!(count > 1000);
+    }
+}
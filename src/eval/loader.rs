@@ -0,0 +1,534 @@
+use super::{
+    features::{InputLayout, ThreatInputs},
+    network::{Architecture, Network, MAX_DENSE_SIZE, MAX_L1_SIZE},
+    set_weights, Weights,
+};
+use crate::policy::network::MAX_HIDDEN;
+use std::{fmt, fs, io};
+
+/// First bytes of every network file
+pub const MAGIC: [u8; 4] = *b"ICTN";
+/// Bumped whenever the layout of the header or the weights that follow it changes
+pub const VERSION: u32 = 5;
+/// Anything deeper than this is far more likely to be a corrupted header than a real network
+const MAX_LAYERS: usize = 8;
+const MAX_BUCKETS: usize = 32;
+/// [`super::output_bucket`] can't tell apart more piece counts than this
+const MAX_OUTPUT_BUCKETS: usize = 32;
+
+/// Describes the weights in a network file.
+///
+/// Stored little endian directly after [`MAGIC`] as the version, whether inputs are mirrored, the index of the
+/// threat inputs in [`ThreatInputs::ALL`], L1 size, number of output buckets, number of dense layers, the size of each
+/// dense layer, one byte per square for the king buckets, and finally the checksum.
+/// The weights follow as little endian f32s in the order [`Network::from_floats`] reads them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    pub arch: Architecture,
+    /// FNV-1a hash of the weights
+    pub checksum: u64,
+}
+
+#[derive(Debug)]
+pub enum NetworkError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The file ended before the header did
+    Truncated,
+    UnknownThreatInputs(u32),
+    /// The file describes a network this engine can't run
+    Architecture(Box<Architecture>, &'static str),
+    /// A weight is too large to be quantized. Layer 0 is the feature transformer.
+    OutOfRange {
+        layer: usize,
+        weight: f32,
+    },
+    /// Some position could push a feature transformer neuron past what its i16 accumulator holds
+    AccumulatorOverflow {
+        neuron: usize,
+        bound: i32,
+    },
+    /// The policy header asks for a hidden layer wider than the engine accepts
+    PolicyHidden(usize),
+    Size {
+        expected: usize,
+        found: usize,
+    },
+    Checksum {
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read network file: {err}"),
+            Self::BadMagic => write!(f, "not a network file (bad magic)"),
+            Self::UnsupportedVersion(v) => write!(f, "network file version {v} is not supported (expected {VERSION})"),
+            Self::Truncated => write!(f, "network file ends in the middle of its header"),
+            Self::UnknownThreatInputs(id) => write!(f, "network uses unknown threat inputs {id}"),
+            Self::Architecture(arch, reason) => write!(f, "can't run a {arch} network: {reason}"),
+            Self::OutOfRange { layer, weight } => {
+                write!(f, "weight {weight} in layer {layer} is out of range for quantization")
+            }
+            Self::AccumulatorOverflow { neuron, bound } => write!(
+                f,
+                "feature transformer neuron {neuron} can reach {bound}, which overflows its i16 accumulator"
+            ),
+            Self::PolicyHidden(hidden) => {
+                write!(f, "policy hidden layer of {hidden} must be between 1 and {MAX_HIDDEN}")
+            }
+            Self::Size { expected, found } => {
+                write!(
+                    f,
+                    "network has {found} bytes of weights but the header describes {expected}"
+                )
+            }
+            Self::Checksum { expected, found } => {
+                write!(
+                    f,
+                    "checksum mismatch, header says {expected:016x} but weights hash to {found:016x}"
+                )
+            }
+        }
+    }
+}
+
+impl From<io::Error> for NetworkError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl Header {
+    pub fn new(arch: Architecture, weights: &[u8]) -> Self {
+        Self {
+            version: VERSION,
+            arch,
+            checksum: checksum(weights),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        let fields = [
+            self.version as usize,
+            usize::from(self.arch.inputs.mirrored),
+            ThreatInputs::ALL
+                .iter()
+                .position(|&t| t == self.arch.inputs.threats)
+                .unwrap(),
+            self.arch.l1_size,
+            self.arch.output_buckets,
+            self.arch.layers.len(),
+        ];
+        for field in fields.into_iter().chain(self.arch.layers.iter().copied()) {
+            bytes.extend_from_slice(&(field as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.arch.inputs.king_buckets);
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Parses a header, returning it along with the number of bytes it took up
+    ///
+    /// # Errors
+    /// If the bytes don't start with a header of the current version
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), NetworkError> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Err(NetworkError::BadMagic);
+        }
+        let mut pos = MAGIC.len();
+        let mut next = |len: usize| {
+            let field = bytes.get(pos..pos + len).ok_or(NetworkError::Truncated)?;
+            pos += len;
+            Ok::<_, NetworkError>(field)
+        };
+        let mut next_u32 = || next(4).map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize);
+
+        let version = next_u32()? as u32;
+        if version != VERSION {
+            return Err(NetworkError::UnsupportedVersion(version));
+        }
+        let mirrored = next_u32()? != 0;
+        let threats_id = next_u32()?;
+        let threats = *ThreatInputs::ALL
+            .get(threats_id)
+            .ok_or(NetworkError::UnknownThreatInputs(threats_id as u32))?;
+        let l1_size = next_u32()?;
+        let output_buckets = next_u32()?;
+        let layer_count = next_u32()?.min(MAX_LAYERS + 1);
+        let layers = (0..layer_count).map(|_| next_u32()).collect::<Result<_, _>>()?;
+        let king_buckets = next(64)?.try_into().unwrap();
+        let checksum = u64::from_le_bytes(next(8)?.try_into().unwrap());
+
+        let header = Self {
+            version,
+            arch: Architecture {
+                inputs: InputLayout {
+                    king_buckets,
+                    mirrored,
+                    threats,
+                },
+                l1_size,
+                layers,
+                output_buckets,
+            },
+            checksum,
+        };
+        Ok((header, pos))
+    }
+}
+
+pub fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Checks that the engine is able to run a network of this shape
+fn validate(arch: &Architecture) -> Result<(), NetworkError> {
+    let reason = if arch.inputs.bucket_count() > MAX_BUCKETS {
+        "too many king buckets"
+    } else if arch.output_buckets == 0 || arch.output_buckets > MAX_OUTPUT_BUCKETS {
+        "unsupported number of output buckets"
+    } else if arch.layers.is_empty() || arch.layers.len() > MAX_LAYERS {
+        "unsupported number of layers"
+    } else if arch.l1_size == 0 || arch.layers.contains(&0) {
+        "layers can't be empty"
+    } else if arch.l1_size > MAX_L1_SIZE {
+        "the feature transformer is too large"
+    } else if arch.dense_shapes().any(|(_, outputs)| outputs > MAX_DENSE_SIZE) {
+        "a dense layer is too wide"
+    } else if !matches!(arch.layers.last(), Some(1 | 3)) {
+        "the last layer must have a single output, or three for win, draw, and loss"
+    } else {
+        return Ok(());
+    };
+    Err(NetworkError::Architecture(Box::new(arch.clone()), reason))
+}
+
+/// Reads and validates a network file, returning its weights
+///
+/// # Errors
+/// If the file can't be read, or its header doesn't match its weights or describes a network the engine can't run
+pub fn read_network(path: &str) -> Result<Network, NetworkError> {
+    let bytes = fs::read(path)?;
+    let (header, header_size) = Header::from_bytes(&bytes)?;
+    let weights = &bytes[header_size..];
+
+    validate(&header.arch)?;
+    let expected = 4 * header.arch.parameter_count();
+    if weights.len() != expected {
+        return Err(NetworkError::Size {
+            expected,
+            found: weights.len(),
+        });
+    }
+    let found = checksum(weights);
+    if header.checksum != found {
+        return Err(NetworkError::Checksum {
+            expected: header.checksum,
+            found,
+        });
+    }
+
+    let floats = weights
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    Ok(Network::from_floats(header.arch, &floats))
+}
+
+/// Switches the engine over to the network in `path`
+///
+/// # Errors
+/// Same as [`read_network`], or if a weight is too large to quantize. The network in use is left untouched.
+pub fn load_network(path: &str) -> Result<(), NetworkError> {
+    let weights = read_network(path).and_then(Weights::new)?;
+    // Networks are leaked since boards and accumulators may still briefly hold on to the old one. We only load a
+    // handful per session, so this is not worth the complexity of tracking them.
+    set_weights(Some(Box::leak(Box::new(weights))));
+    Ok(())
+}
+
+/// Goes back to the network that was embedded in the binary at compile time
+pub fn use_embedded_network() {
+    set_weights(None);
+}
+
+/// Wraps raw weights, such as bullet's `raw.bin`, with a header so they can be loaded at runtime. The architecture
+/// defaults to the embedded network's.
+///
+/// Usage: pack <raw weights> <output file> [l1 size] [layer sizes...]
+pub fn pack(args: &[String]) {
+    let (Some(input), Some(output)) = (args.first(), args.get(1)) else {
+        println!("Usage: pack <raw weights> <output file> [l1 size] [layer sizes...]");
+        return;
+    };
+    let mut arch = Architecture::embedded();
+    if let Some(l1_size) = args.get(2) {
+        arch.l1_size = l1_size.parse().expect("Invalid l1 size");
+        arch.layers = args[3..]
+            .iter()
+            .map(|x| x.parse().expect("Invalid layer size"))
+            .collect();
+    }
+    if let Err(err) = validate(&arch) {
+        println!("{err}");
+        return;
+    }
+
+    let weights = fs::read(input).expect("Weights file not found");
+    match write_network(arch, weights, output) {
+        Ok(()) => println!("Wrote {output}"),
+        Err(err) => println!("Could not pack {input}: {err}"),
+    }
+}
+
+/// Strips the padding bullet adds to the end of its raw weights, which it rounds up to a multiple of 64 bytes.
+///
+/// What's left is exactly what [`Network::from_floats`] reads, and what `bins/raw.bin` has to hold.
+///
+/// # Errors
+/// If the weights are not the right size for `arch`
+pub fn trim_raw_weights(arch: &Architecture, mut weights: Vec<u8>) -> Result<Vec<u8>, NetworkError> {
+    let expected = 4 * arch.parameter_count();
+    if weights.len() < expected || weights.len() >= expected + 64 {
+        return Err(NetworkError::Size {
+            expected,
+            found: weights.len(),
+        });
+    }
+    weights.truncate(expected);
+    Ok(weights)
+}
+
+/// Writes raw weights to `path` behind a header describing `arch`, so the engine can load them at runtime
+///
+/// # Errors
+/// If `arch` can't be run by the engine, the weights don't fit it, or the file can't be written
+pub fn write_network(arch: Architecture, weights: Vec<u8>, path: &str) -> Result<(), NetworkError> {
+    validate(&arch)?;
+    let weights = trim_raw_weights(&arch, weights)?;
+    let mut bytes = Header::new(arch, &weights).to_bytes();
+    bytes.extend_from_slice(&weights);
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod loader_tests {
+    use super::{
+        checksum, load_network, use_embedded_network, Architecture, Header, InputLayout, NetworkError, ThreatInputs,
+    };
+    use crate::eval::{
+        network::{MAX_DENSE_SIZE, MAX_L1_SIZE},
+        quantized::QuantizedNetwork,
+        weights, EMBEDDED, INPUT_LAYOUT,
+    };
+
+    fn write_network(name: &str, header: &Header, weights: &[u8]) -> String {
+        let path = std::env::temp_dir().join(name);
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(weights);
+        std::fs::write(&path, &bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header::new(Architecture::embedded(), &[1, 2, 3]);
+        let bytes = header.to_bytes();
+        assert_eq!(Header::from_bytes(&bytes).unwrap(), (header, bytes.len()));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(
+            Header::from_bytes(b"not a network"),
+            Err(NetworkError::BadMagic)
+        ));
+
+        let mut bytes = Header::new(Architecture::embedded(), &[]).to_bytes();
+        assert!(matches!(
+            Header::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(NetworkError::Truncated)
+        ));
+        bytes[4] = 99;
+        assert!(matches!(
+            Header::from_bytes(&bytes),
+            Err(NetworkError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn loads_other_architectures() {
+        // A scalar and a WDL head
+        for head in [1, 3] {
+            let arch = Architecture {
+                layers: vec![8, head],
+                l1_size: 64,
+                output_buckets: 8,
+                ..Architecture::embedded()
+            };
+            let weights = (0..arch.parameter_count())
+                .flat_map(|i| ((i % 7) as f32 / 100.).to_le_bytes())
+                .collect::<Vec<_>>();
+            let path = write_network("ictn_small.bin", &Header::new(arch.clone(), &weights), &weights);
+            let net = super::read_network(&path).unwrap();
+            assert_eq!(net.architecture(), &arch);
+            assert_eq!(net.architecture().wdl_head(), head == 3);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_file() {
+        let arch = Architecture {
+            layers: vec![1],
+            l1_size: 8,
+            ..Architecture::embedded()
+        };
+        let weights = vec![0u8; 4 * arch.parameter_count()];
+        let mut header = Header::new(arch, &weights);
+        header.checksum ^= 1;
+        let path = write_network("ictn_corrupted.bin", &header, &weights);
+        let err = super::read_network(&path).unwrap_err();
+        assert!(matches!(err, NetworkError::Checksum { found, .. } if found == checksum(&weights)));
+
+        let path = write_network("ictn_corrupted.bin", &header, &weights[4..]);
+        let err = super::read_network(&path).unwrap_err();
+        assert!(matches!(err, NetworkError::Size { .. }));
+
+        header.arch.layers = vec![2];
+        let path = write_network("ictn_corrupted.bin", &header, &weights);
+        let err = super::read_network(&path).unwrap_err();
+        assert!(matches!(err, NetworkError::Architecture(..)));
+
+        // Too big for the fixed size buffers evaluation runs in
+        for arch in [
+            Architecture {
+                l1_size: MAX_L1_SIZE + 1,
+                ..header.arch.clone()
+            },
+            Architecture {
+                layers: vec![MAX_DENSE_SIZE + 1, 1],
+                ..header.arch.clone()
+            },
+            Architecture {
+                layers: vec![3],
+                output_buckets: MAX_DENSE_SIZE / 3 + 1,
+                ..header.arch
+            },
+        ] {
+            let err = super::validate(&arch).unwrap_err();
+            assert!(matches!(err, NetworkError::Architecture(..)), "{arch}");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_unquantizable_weights() {
+        let arch = Architecture {
+            layers: vec![1],
+            l1_size: 8,
+            ..Architecture::embedded()
+        };
+        let mut floats = vec![0.01f32; arch.parameter_count()];
+        // The first weight of the output layer, far past what fits in an i8 at scale QB
+        let dense = arch.input_size() * arch.l1_size + arch.l1_size;
+        floats[dense] = 3.;
+        let weights = floats.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+        let path = write_network("ictn_out_of_range.bin", &Header::new(arch, &weights), &weights);
+
+        assert!(super::read_network(&path).is_ok());
+        let err = load_network(&path).unwrap_err();
+        assert!(matches!(err, NetworkError::OutOfRange { layer: 1, weight } if (weight - 3.).abs() < f32::EPSILON));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_accumulator_overflow() {
+        let arch = Architecture {
+            layers: vec![1],
+            l1_size: 8,
+            inputs: InputLayout {
+                threats: ThreatInputs::AttackerTypes,
+                ..INPUT_LAYOUT
+            },
+            ..Architecture::embedded()
+        };
+        let ft_weights = arch.input_size() * arch.l1_size;
+        let weights_for = |ft_weight: f32| {
+            let mut floats = vec![0.01f32; arch.parameter_count()];
+            floats[..ft_weights].fill(ft_weight);
+            floats.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>()
+        };
+
+        // Every weight fits an i16 on its own, but 224 active features of 0.6 * 255 each don't fit together
+        let weights = weights_for(0.6);
+        let path = write_network("ictn_overflow.bin", &Header::new(arch.clone(), &weights), &weights);
+        let err = load_network(&path).unwrap_err();
+        assert!(
+            matches!(err, NetworkError::AccumulatorOverflow { neuron: 0, .. }),
+            "{err}"
+        );
+
+        let weights = weights_for(0.5);
+        let path = write_network("ictn_overflow.bin", &Header::new(arch, &weights), &weights);
+        let net = super::read_network(&path).unwrap();
+        assert!(QuantizedNetwork::from_float(&net).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failed_load_keeps_network() {
+        // A copy of the embedded network, so tests evaluating in parallel see the same scores
+        let path = write_network(
+            "ictn_copy.bin",
+            &Header::new(Architecture::embedded(), EMBEDDED),
+            EMBEDDED,
+        );
+        load_network(&path).unwrap();
+        let generation = weights().generation;
+
+        let err = load_network("ictn_does_not_exist.bin").unwrap_err();
+        assert!(matches!(err, NetworkError::Io(_)), "{err}");
+        assert_eq!(weights().generation, generation);
+
+        use_embedded_network();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn writes_padded_weights() {
+        let arch = Architecture {
+            layers: vec![1],
+            l1_size: 8,
+            ..Architecture::embedded()
+        };
+        let size = 4 * arch.parameter_count();
+        // bullet pads to a multiple of 64 bytes
+        let mut weights = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        weights.resize(size.next_multiple_of(64), 0);
+
+        let path = std::env::temp_dir()
+            .join("ictn_padded.bin")
+            .to_str()
+            .unwrap()
+            .to_string();
+        super::write_network(arch.clone(), weights.clone(), &path).unwrap();
+        let (header, header_size) = Header::from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(header, Header::new(arch.clone(), &weights[..size]));
+        assert_eq!(std::fs::metadata(&path).unwrap().len() as usize, header_size + size);
+        std::fs::remove_file(path).unwrap();
+
+        weights.truncate(size - 4);
+        let err = super::trim_raw_weights(&arch, weights).unwrap_err();
+        assert!(matches!(err, NetworkError::Size { expected, .. } if expected == size));
+    }
+}
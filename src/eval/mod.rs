@@ -1,9 +1,135 @@
-use self::network::Network;
+use self::{
+    features::{InputLayout, ThreatInputs},
+    loader::NetworkError,
+    network::{Architecture, Network},
+    quantized::QuantizedNetwork,
+};
+use std::{
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicU32, Ordering},
+        LazyLock,
+    },
+};
 
+pub mod accumulator;
+pub mod features;
+pub mod loader;
 pub mod network;
+pub mod quantized;
+pub mod simd;
 pub mod util;
 
-pub const INPUT_SIZE: usize = 768 * 4;
+/// A single bucket, for networks that don't know where the king is
+pub const NO_BUCKETS: [u8; 64] = [0; 64];
+/// Separates castled, uncastled and advanced kings. Only the a-d files matter when mirroring.
+#[rustfmt::skip]
+pub const FOUR_BUCKETS: [u8; 64] = [
+    0, 0, 1, 1, 1, 1, 0, 0,
+    2, 2, 2, 2, 2, 2, 2, 2,
+    3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3,
+];
+
+/// Input set of the embedded network, and of the networks the trainer produces.
+///
+/// Buckets are indexed by the king's square from its own side's point of view, so a1 is always the queenside corner
+/// of the king's back rank.
+pub const INPUT_LAYOUT: InputLayout = InputLayout {
+    king_buckets: NO_BUCKETS,
+    mirrored: false,
+    threats: ThreatInputs::Attacked,
+};
+pub const INPUT_SIZE: usize = INPUT_LAYOUT.input_size();
+/// Architecture of the network embedded in the binary. Networks loaded at runtime describe their own in their header.
 pub const L1_SIZE: usize = 768;
+/// Outputs of each dense layer after the feature transformer
+pub const LAYER_SIZES: [usize; 3] = [16, 32, 1];
+/// Number of heads in the last layer of the embedded network, see [`output_bucket`]
+pub const OUTPUT_BUCKETS: usize = 1;
+
+/// Picks which of `buckets` heads evaluates a position with `piece_count` pieces on the board, kings included.
+///
+/// The trainer uses this too, so the engine runs a position through the same head it was trained with.
+pub const fn output_bucket(piece_count: u32, buckets: usize) -> usize {
+    let per_bucket = 32usize.div_ceil(buckets);
+    let bucket = (piece_count as usize).saturating_sub(2) / per_bucket;
+    if bucket < buckets {
+        bucket
+    } else {
+        buckets - 1
+    }
+}
+
+/// Network used when no `EvalFile` is set
+static EMBEDDED: &[u8] = include_bytes!("../../bins/raw.bin");
+const _: () = {
+    let mut count = INPUT_SIZE * L1_SIZE + L1_SIZE;
+    let mut inputs = 2 * L1_SIZE;
+    let mut i = 0;
+    while i < LAYER_SIZES.len() {
+        let outputs = if i + 1 == LAYER_SIZES.len() {
+            LAYER_SIZES[i] * OUTPUT_BUCKETS
+        } else {
+            LAYER_SIZES[i]
+        };
+        count += inputs * outputs + outputs;
+        inputs = LAYER_SIZES[i];
+        i += 1;
+    }
+    assert!(
+        EMBEDDED.len() == 4 * count,
+        "bins/raw.bin doesn't match the embedded architecture"
+    );
+};
+/// Quantizing can't fail here for a network that made it into `bins/raw.bin`: the trainer's export loads every
+/// network it writes through the engine, and `embedded_network_quantizes` checks the one that's embedded.
+static EMBEDDED_WEIGHTS: LazyLock<Weights> =
+    LazyLock::new(|| Weights::new(embedded_network()).expect("Embedded network can't be quantized"));
+
+fn embedded_network() -> Network {
+    let floats = EMBEDDED
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    Network::from_floats(Architecture::embedded(), &floats)
+}
+/// Weights loaded at runtime, or null to use the embedded network
+static LOADED: AtomicPtr<Weights> = AtomicPtr::new(ptr::null_mut());
+static NEXT_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// A float network along with its quantized copy, which is built from it so the two can never get out of sync
+pub struct Weights {
+    /// Changes every time a network is loaded, so accumulators can tell when they need to start over
+    generation: u32,
+    float: Network,
+    quantized: QuantizedNetwork,
+}
+
+impl Weights {
+    fn new(float: Network) -> Result<Self, NetworkError> {
+        Ok(Self {
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            quantized: QuantizedNetwork::from_float(&float)?,
+            float,
+        })
+    }
+}
+
+/// The network the engine is currently using
+fn weights() -> &'static Weights {
+    let loaded = LOADED.load(Ordering::Acquire);
+    if loaded.is_null() {
+        &EMBEDDED_WEIGHTS
+    } else {
+        unsafe { &*loaded }
+    }
+}
 
-static NET: Network = unsafe { std::mem::transmute(*include_bytes!("../../bins/raw.bin")) };
+fn set_weights(weights: Option<&'static mut Weights>) {
+    LOADED.store(weights.map_or(ptr::null_mut(), ptr::from_mut), Ordering::Release);
+}
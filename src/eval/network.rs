@@ -1,120 +1,258 @@
-use super::{util::f32_update, INPUT_SIZE, L1_SIZE, NET};
-
-use crate::{
-    board::Board,
-    types::{
-        bitboard::Bitboard,
-        pieces::{Color, PieceName},
-    },
-    value::SCALE,
+use super::{
+    features::{features, threat_maps, InputLayout, ThreatInputs},
+    output_bucket,
+    util::f32_update,
+    INPUT_LAYOUT, L1_SIZE, LAYER_SIZES, OUTPUT_BUCKETS,
 };
-use arrayvec::ArrayVec;
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub(super) struct Layer<const M: usize, const N: usize, T> {
-    pub(super) weights: [[T; N]; M],
-    pub(super) bias: [T; N],
+use crate::{board::Board, types::pieces::PieceName, value::SCALE};
+use std::fmt;
+
+/// Largest feature transformer the engine runs. Accumulators and activations live in fixed size buffers of this
+/// size, so evaluating a position never allocates.
+pub const MAX_L1_SIZE: usize = 1024;
+/// Widest dense layer the engine runs, counting the outputs of every bucket of the last layer
+pub const MAX_DENSE_SIZE: usize = 256;
+
+/// Shape of a network.
+///
+/// A feature transformer from the inputs of `inputs` to `l1_size` is applied from both perspectives, followed by
+/// dense layers with `layers` outputs each. The last layer has either a single output, or three for win, draw, and
+/// loss logits. It has `output_buckets` copies of them that are picked between by piece count.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Architecture {
+    pub inputs: InputLayout,
+    pub l1_size: usize,
+    pub layers: Vec<usize>,
+    pub output_buckets: usize,
 }
 
-impl<const M: usize, const N: usize> Layer<M, N, f32> {
-    /// This function returns transformed feature vectors in the order [stm, nstm] instead of the commonly seen
-    /// [`Color::White`, `Color::Black`]. This simplifies the calculation of which weights to use in the next function call.
-    fn transform(&self, board: &Board) -> [[f32; N]; 2] {
-        let mut output = [self.bias; 2];
-        let mut stm_feats = ArrayVec::<usize, 32>::new();
-        let mut xstm_feats = ArrayVec::<usize, 32>::new();
-
-        let threats = board.threats(!board.stm());
-        let defenders = board.threats(board.stm());
-        for sq in board.occupancies() {
-            let piece = board.piece_at(sq);
-            let is_opp = piece.color() != board.stm();
-            let map_feature = |feat, threats: Bitboard, defenders: Bitboard| {
-                2 * 768 * usize::from(defenders.contains(sq)) + 768 * usize::from(threats.contains(sq)) + feat
-            };
-
-            let stm_feat = 384 * usize::from(is_opp)
-                + 64 * usize::from(piece.name())
-                + if board.stm() == Color::White {
-                    usize::from(sq)
-                } else {
-                    usize::from(sq.flip_vertical())
-                };
-            let xstm_feat = 384 * usize::from(!is_opp)
-                + 64 * usize::from(piece.name())
-                + if board.stm() == Color::Black {
-                    usize::from(sq)
-                } else {
-                    usize::from(sq.flip_vertical())
-                };
-            stm_feats.push(map_feature(stm_feat, threats, defenders));
-            xstm_feats.push(map_feature(xstm_feat, defenders, threats));
+impl Architecture {
+    /// Architecture of the network embedded in the binary
+    pub fn embedded() -> Self {
+        Self {
+            inputs: INPUT_LAYOUT,
+            l1_size: L1_SIZE,
+            layers: LAYER_SIZES.to_vec(),
+            output_buckets: OUTPUT_BUCKETS,
         }
+    }
 
-        f32_update(&mut output[0], &stm_feats, &[]);
-        f32_update(&mut output[1], &xstm_feats, &[]);
-        output
+    pub const fn input_size(&self) -> usize {
+        self.inputs.input_size()
+    }
+
+    /// (inputs, outputs) of every dense layer, in order. The outputs of every bucket of the last layer are counted.
+    pub fn dense_shapes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let inputs = std::iter::once(2 * self.l1_size).chain(self.layers.iter().copied());
+        let last = self.layers.len().saturating_sub(1);
+        let outputs = self.layers.iter().enumerate().map(
+            move |(i, &size)| {
+                if i == last {
+                    size * self.output_buckets
+                } else {
+                    size
+                }
+            },
+        );
+        inputs.zip(outputs)
+    }
+
+    /// Index of the head in the last layer that evaluates `board`
+    pub fn output_bucket(&self, board: &Board) -> usize {
+        output_bucket(board.occupancies().count_bits() as u32, self.output_buckets)
+    }
+
+    /// Whether the network predicts win, draw, and loss chances instead of a single evaluation
+    pub fn wdl_head(&self) -> bool {
+        self.layers.last() == Some(&3)
+    }
+
+    /// Number of weights and biases in a network of this shape
+    pub fn parameter_count(&self) -> usize {
+        self.input_size() * self.l1_size + self.l1_size + self.dense_shapes().map(|(m, n)| m * n + n).sum::<usize>()
     }
 }
 
-impl<const M: usize, const N: usize> Layer<M, N, f32> {
-    fn forward(&self, input: [f32; M]) -> [f32; N] {
-        let mut output = self.bias;
-        for (&i, col) in input.iter().zip(self.weights.iter()) {
-            for (o, c) in output.iter_mut().zip(col.iter()) {
-                *o += c * screlu(i);
+/// What the last layer of a network says about a position, from the side to move's point of view
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetOutput {
+    /// Evaluation in centipawns, before it is scaled by material
+    Cp(f32),
+    /// Win, draw, and loss chances
+    Wdl([f32; 3]),
+}
+
+impl NetOutput {
+    /// Interprets the outputs of a single head, which are in units of the output scale
+    pub fn from_head(head: &[f32]) -> Self {
+        match *head {
+            [x] => Self::Cp(x * SCALE),
+            [w, d, l] => {
+                let max = w.max(d).max(l);
+                let exps = [w, d, l].map(|x| (x - max).exp());
+                let sum = exps.iter().sum::<f32>();
+                Self::Wdl(exps.map(|x| x / sum))
             }
+            _ => unreachable!("Heads are checked to have one or three outputs when a network is loaded"),
         }
-        output
+    }
+
+    /// Centipawn evaluation. WDL heads are converted through their expected score with the same sigmoid scalar
+    /// networks are trained against.
+    pub fn cp(self) -> f32 {
+        match self {
+            Self::Cp(cp) => cp,
+            Self::Wdl([w, d, _]) => {
+                let score = (w + d / 2.).clamp(1e-6, 1. - 1e-6);
+                SCALE * (score / (1. - score)).ln()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}->{}x2", self.input_size(), self.l1_size)?;
+        for size in &self.layers {
+            write!(f, "->{size}")?;
+        }
+        write!(f, " ({} king buckets", self.inputs.bucket_count())?;
+        if self.inputs.mirrored {
+            write!(f, ", mirrored")?;
+        }
+        if self.inputs.threats == ThreatInputs::AttackerTypes {
+            write!(f, ", attacker types")?;
+        }
+        if self.output_buckets > 1 {
+            write!(f, ", {} output buckets", self.output_buckets)?;
+        }
+        write!(f, ")")
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-#[repr(C)]
-pub(super) struct PerspectiveLayer<const M: usize, const N: usize, T> {
-    pub(super) weights: [[[T; N]; M]; 2],
-    pub(super) bias: [T; N],
+/// A layer of any size. `weights` holds one row of `bias.len()` weights per input.
+#[derive(Debug, Clone)]
+pub(super) struct Layer<W, B = W> {
+    pub(super) weights: Vec<W>,
+    pub(super) bias: Vec<B>,
 }
 
-impl<const M: usize, const N: usize> PerspectiveLayer<M, N, f32> {
-    fn forward(&self, input: [[f32; M]; 2]) -> [f32; N] {
-        let mut output = self.bias;
+impl<W, B> Layer<W, B> {
+    pub(super) const fn outputs(&self) -> usize {
+        self.bias.len()
+    }
 
-        for (input, weights) in input.iter().zip(self.weights.iter()) {
-            for (&i, col) in input.iter().zip(weights.iter()) {
-                for (o, c) in output.iter_mut().zip(col.iter()) {
-                    *o += c * screlu(i);
-                }
-            }
+    /// Weights connecting input `i` to every output
+    pub(super) fn row(&self, i: usize) -> &[W] {
+        &self.weights[i * self.outputs()..(i + 1) * self.outputs()]
+    }
+
+    pub(super) fn rows(&self) -> impl Iterator<Item = &[W]> {
+        self.weights.chunks_exact(self.outputs())
+    }
+}
+
+impl Layer<f32> {
+    /// Takes the next `m * n + n` floats off the front of `floats`, weights first
+    fn read(floats: &mut &[f32], m: usize, n: usize) -> Self {
+        let (weights, rest) = floats.split_at(m * n);
+        let (bias, rest) = rest.split_at(n);
+        *floats = rest;
+        Self {
+            weights: weights.to_vec(),
+            bias: bias.to_vec(),
         }
+    }
 
+    /// This function returns transformed feature vectors in the order [stm, nstm] instead of the commonly seen
+    /// [`Color::White`, `Color::Black`]. This simplifies the calculation of which weights to use in the next function call.
+    /// Only the first [`Layer::outputs`] values of each are used.
+    pub(super) fn transform(&self, board: &Board, layout: &InputLayout) -> [[f32; MAX_L1_SIZE]; 2] {
+        let mut output = [[0.; MAX_L1_SIZE]; 2];
+        let threats = threat_maps(board);
+
+        for (acc, perspective) in output.iter_mut().zip([board.stm(), !board.stm()]) {
+            let acc = &mut acc[..self.outputs()];
+            acc.copy_from_slice(&self.bias);
+            f32_update(self, acc, &features(board, perspective, &threats, layout), &[]);
+        }
         output
     }
+
+    fn forward(&self, input: &[f32], output: &mut [f32]) {
+        output.copy_from_slice(&self.bias);
+        for (&i, row) in input.iter().zip(self.rows()) {
+            for (o, c) in output.iter_mut().zip(row) {
+                *o += c * i;
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
+#[derive(Debug, Clone)]
 pub struct Network {
-    pub(super) ft: Layer<INPUT_SIZE, L1_SIZE, f32>,
-    pub(super) l1: PerspectiveLayer<L1_SIZE, 16, f32>,
-    pub(super) l2: Layer<16, 32, f32>,
-    pub(super) l3: Layer<32, 1, f32>,
+    pub(super) arch: Architecture,
+    pub(super) ft: Layer<f32>,
+    /// The first layer takes both perspectives' accumulators, stm first
+    pub(super) layers: Vec<Layer<f32>>,
+}
+
+impl Network {
+    /// Builds a network from floats in the order bullet writes them: the weights of each layer followed by its
+    /// biases, starting with the feature transformer
+    pub fn from_floats(arch: Architecture, mut floats: &[f32]) -> Self {
+        assert_eq!(
+            floats.len(),
+            arch.parameter_count(),
+            "Wrong number of weights for {arch}"
+        );
+        let ft = Layer::read(&mut floats, arch.input_size(), arch.l1_size);
+        let layers = arch
+            .dense_shapes()
+            .map(|(m, n)| Layer::read(&mut floats, m, n))
+            .collect();
+        Self { arch, ft, layers }
+    }
+
+    pub const fn architecture(&self) -> &Architecture {
+        &self.arch
+    }
+
+    /// Runs everything after the feature transformer, given accumulators in [stm, nstm] order. Only the head of
+    /// `bucket` is used.
+    pub(super) fn out(&self, ft: [&[f32]; 2], bucket: usize) -> NetOutput {
+        let mut input = [0.; 2 * MAX_L1_SIZE];
+        let mut output = [0.; MAX_DENSE_SIZE];
+        // Two separate loops, since chaining the halves into one iterator keeps the compiler from vectorizing it
+        for (half, ft) in input.chunks_exact_mut(ft[0].len()).zip(ft) {
+            for (x, &a) in half.iter_mut().zip(ft) {
+                *x = screlu(a);
+            }
+        }
+        let mut len = ft[0].len() + ft[1].len();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if i > 0 {
+                for (x, &o) in input.iter_mut().zip(&output[..len]) {
+                    *x = screlu(o);
+                }
+            }
+            layer.forward(&input[..len], &mut output[..layer.outputs()]);
+            len = layer.outputs();
+        }
+        let head = self.arch.layers[self.arch.layers.len() - 1];
+        NetOutput::from_head(&output[bucket * head..(bucket + 1) * head])
+    }
 }
 
 impl Board {
-    pub fn raw_eval(&self) -> f32 {
-        let ft = NET.ft.transform(self);
-        let l1 = NET.l1.forward(ft);
-        let l2 = NET.l2.forward(l1);
-        let l3 = NET.l3.forward(l2);
-        l3[0] * SCALE
+    pub fn scaled_eval(&self) -> i32 {
+        self.scale_eval(self.raw_eval())
     }
 
     /// Credit to viridithas for these values and concepts
-    pub fn scaled_eval(&self) -> i32 {
-        let raw = self.raw_eval() as i32;
-        raw * self.mat_scale() / 1024
+    pub fn scale_eval(&self, raw: f32) -> i32 {
+        raw as i32 * self.mat_scale() / 1024
     }
 
     fn mat_scale(&self) -> i32 {
@@ -129,3 +267,38 @@ impl Board {
 fn screlu(x: f32) -> f32 {
     x.clamp(0., 1.).powi(2)
 }
+
+#[cfg(test)]
+mod network_tests {
+    use super::NetOutput;
+    use crate::eval::output_bucket;
+
+    #[test]
+    fn output_buckets_cover_every_piece_count() {
+        for buckets in 1..=32 {
+            let picked = (2..=32).map(|count| output_bucket(count, buckets)).collect::<Vec<_>>();
+            assert!(picked.windows(2).all(|w| w[0] <= w[1]));
+            assert_eq!(picked[0], 0);
+            assert!(picked.iter().all(|&b| b < buckets));
+        }
+        assert_eq!(output_bucket(32, 8), 7);
+        assert_eq!(output_bucket(3, 8), 0);
+    }
+
+    #[test]
+    fn wdl_head_outputs() {
+        let NetOutput::Wdl(wdl) = NetOutput::from_head(&[0., 0., 0.]) else {
+            panic!("three outputs should make a WDL head");
+        };
+        assert!(wdl.iter().all(|&p| (p - 1. / 3.).abs() < 1e-6));
+        assert!(NetOutput::Wdl(wdl).cp().abs() < 1e-3);
+
+        let NetOutput::Wdl([w, d, l]) = NetOutput::from_head(&[2., 1., -1.]) else {
+            panic!("three outputs should make a WDL head");
+        };
+        assert!((w + d + l - 1.).abs() < 1e-6);
+        assert!(w > d && d > l);
+        assert!(NetOutput::Wdl([w, d, l]).cp() > 0.);
+        assert_eq!(NetOutput::from_head(&[0.5]).cp().to_bits(), 200f32.to_bits());
+    }
+}
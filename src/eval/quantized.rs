@@ -0,0 +1,326 @@
+use super::{
+    accumulator::Accumulator,
+    features::{features, threat_maps, InputLayout},
+    loader::NetworkError,
+    network::{Layer, NetOutput, Network, MAX_DENSE_SIZE, MAX_L1_SIZE},
+    simd,
+    util::i16_update,
+    weights,
+};
+use crate::board::Board;
+
+/// Scale of the feature transformer weights, and of every clipped activation
+pub const QA: i32 = 255;
+/// Scale of the dense layer weights. Weights are clipped to ±1.98 in training, so they fit in an i8.
+pub const QB: i32 = 64;
+/// Scale of every dense layer output.
+///
+/// Squaring a clipped activation at scale `QA` and multiplying by a weight at scale `QB` lands here without
+/// needing to divide anything back down, which keeps the full precision of the square.
+pub const QO: i32 = QA * QA * QB;
+
+/// Integer counterpart of [`Network`], built from the float weights when a network is loaded. Only the trainer's
+/// float output is ever read, so the scales here are free to change without retraining.
+pub struct QuantizedNetwork {
+    pub(super) ft: Layer<i16>,
+    layers: Vec<Layer<i8, i32>>,
+    /// Whether each layer's outputs could get past an i32, in which case it runs through [`Layer::forward_wide`]
+    wide: Vec<bool>,
+    output_buckets: usize,
+}
+
+impl Layer<i16> {
+    /// Accumulators in [stm, nstm] order, see the float version in network.rs
+    fn transform(&self, board: &Board, layout: &InputLayout) -> [[i16; MAX_L1_SIZE]; 2] {
+        let mut output = [[0; MAX_L1_SIZE]; 2];
+        let threats = threat_maps(board);
+
+        for (acc, perspective) in output.iter_mut().zip([board.stm(), !board.stm()]) {
+            let acc = &mut acc[..self.outputs()];
+            acc.copy_from_slice(&self.bias);
+            i16_update(self, acc, &features(board, perspective, &threats, layout), &[]);
+        }
+        output
+    }
+}
+
+impl Layer<i8, i32> {
+    /// Takes inputs that have already been activated
+    fn forward(&self, input: &[i32], output: &mut [i32]) {
+        output.copy_from_slice(&self.bias);
+        simd::affine(output, input, &self.weights);
+    }
+
+    /// Same as [`Layer::forward`], but sums in i64 and saturates the outputs. Saturating leaves plenty of room
+    /// past where [`dense_screlu`] clips, and past any eval the search could tell apart, so nothing is lost.
+    fn forward_wide(&self, input: &[i32], output: &mut [i32]) {
+        let mut wide = [0; MAX_DENSE_SIZE];
+        let wide = &mut wide[..output.len()];
+        for (o, &b) in wide.iter_mut().zip(&self.bias) {
+            *o = i64::from(b);
+        }
+        for (&i, row) in input.iter().zip(self.rows()) {
+            for (o, &w) in wide.iter_mut().zip(row) {
+                *o += i64::from(i) * i64::from(w);
+            }
+        }
+        for (o, &w) in output.iter_mut().zip(wide.iter()) {
+            *o = w.clamp(-DENSE_LIMIT, DENSE_LIMIT) as i32;
+        }
+    }
+}
+
+/// Integer `SCReLU` of an accumulator value. Input is at scale `QA`, output is at scale `QA * QA`.
+///
+/// Every activation is between 0 and `QA * QA`, so a dense output lies between its bias plus `QA * QA` times the sum
+/// of its negative weights and its bias plus `QA * QA` times the sum of its positive weights. With weights clipped to
+/// ±1.98 that could reach 1536 * 255² * 127 for the first layer, far past an i32. [`fits_i32`] works out when a
+/// network is loaded whether a layer can get there, and only those layers pay for summing in i64.
+fn ft_screlu(x: i16) -> i32 {
+    let x = i32::from(x).clamp(0, QA);
+    x * x
+}
+
+/// Integer `SCReLU` of a dense layer output. Input is at scale `QO`, output is at scale `QA * QA`.
+fn dense_screlu(x: i32) -> i32 {
+    let x = ((x + QA * QB / 2) / (QA * QB)).clamp(0, QA);
+    x * x
+}
+
+impl QuantizedNetwork {
+    /// Rounds every weight of a float network to its quantized representation
+    ///
+    /// # Errors
+    /// If a weight doesn't fit its integer type once scaled
+    pub fn from_float(net: &Network) -> Result<Self, NetworkError> {
+        let layers = net
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, l)| {
+                Ok(Layer {
+                    weights: quantize(&l.weights, QB, i + 1)?,
+                    bias: quantize(&l.bias, QO, i + 1)?,
+                })
+            })
+            .collect::<Result<Vec<_>, NetworkError>>()?;
+        let ft = Layer {
+            weights: quantize(&net.ft.weights, QA, 0)?,
+            bias: quantize(&net.ft.bias, QA, 0)?,
+        };
+        check_accumulator_range(&ft, net.arch.inputs.threats.max_active())?;
+        Ok(Self {
+            ft,
+            wide: layers.iter().map(|l| !fits_i32(l)).collect(),
+            layers,
+            output_buckets: net.arch.output_buckets,
+        })
+    }
+
+    /// Runs everything after the feature transformer, given accumulators in [stm, nstm] order. Only the head of
+    /// `bucket` is used.
+    pub(super) fn out(&self, ft: [&[i16]; 2], bucket: usize) -> NetOutput {
+        let mut input = [0; 2 * MAX_L1_SIZE];
+        let mut output = [0; MAX_DENSE_SIZE];
+        // Two separate loops, since chaining the halves into one iterator keeps the compiler from vectorizing it
+        for (half, ft) in input.chunks_exact_mut(ft[0].len()).zip(ft) {
+            for (x, &a) in half.iter_mut().zip(ft) {
+                *x = ft_screlu(a);
+            }
+        }
+        let mut len = ft[0].len() + ft[1].len();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if i > 0 {
+                for (x, &o) in input.iter_mut().zip(&output[..len]) {
+                    *x = dense_screlu(o);
+                }
+            }
+            if self.wide[i] {
+                layer.forward_wide(&input[..len], &mut output[..layer.outputs()]);
+            } else {
+                layer.forward(&input[..len], &mut output[..layer.outputs()]);
+            }
+            len = layer.outputs();
+        }
+        let head = len / self.output_buckets;
+        let mut logits = [0.; 3];
+        for (l, &x) in logits.iter_mut().zip(&output[bucket * head..(bucket + 1) * head]) {
+            *l = x as f32 / QO as f32;
+        }
+        NetOutput::from_head(&logits[..head])
+    }
+}
+
+/// Scales and rounds the weights of layer `layer`, where layer 0 is the feature transformer
+fn quantize<T: TryFrom<i32>>(f: &[f32], scale: i32, layer: usize) -> Result<Vec<T>, NetworkError> {
+    f.iter()
+        .map(|&f| {
+            let val = (f * scale as f32).round();
+            // Float to int casts saturate, so anything past the range of an i32 is caught by the conversion below
+            T::try_from(val as i32).map_err(|_| NetworkError::OutOfRange { layer, weight: f })
+        })
+        .collect()
+}
+
+/// Makes sure no position can push an accumulator out of the range of an i16. The kernels add and subtract with
+/// wrapping arithmetic, so values in between are allowed to wrap as long as the final sum fits, but a sum that
+/// doesn't fit would silently wrap around to a value with the wrong sign.
+///
+/// The bound for each neuron is its bias plus the `max_active` largest weights feeding into it, which is only
+/// reached if every one of those features can be active at once, so it errs on the side of rejecting a network.
+fn check_accumulator_range(ft: &Layer<i16>, max_active: usize) -> Result<(), NetworkError> {
+    let mut column = Vec::with_capacity(ft.weights.len() / ft.outputs());
+    for (neuron, &bias) in ft.bias.iter().enumerate() {
+        column.clear();
+        column.extend(ft.rows().map(|row| i32::from(row[neuron]).abs()));
+        let active = max_active.min(column.len());
+        if active < column.len() {
+            column.select_nth_unstable_by(active, |a, b| b.cmp(a));
+        }
+        let bound = i32::from(bias).abs() + column[..active].iter().sum::<i32>();
+        if bound > i32::from(i16::MAX) {
+            return Err(NetworkError::AccumulatorOverflow { neuron, bound });
+        }
+    }
+    Ok(())
+}
+
+/// Largest dense output that's left as it is. Outputs are only ever divided back down after this, but
+/// [`dense_screlu`] rounds by adding half of `QA * QB` first, which must not overflow either.
+const DENSE_LIMIT: i64 = i32::MAX as i64 - (QA * QB) as i64;
+
+/// Whether a dense layer's outputs always fit in an i32, given that every activation going into it is between 0 and
+/// `QA * QA`. The kernels wrap, so only the final sums matter. The bound is what the outputs reach with every input
+/// feeding a positive weight clipped and every other input at 0, or the other way around.
+fn fits_i32(layer: &Layer<i8, i32>) -> bool {
+    let mut lo = layer.bias.iter().map(|&b| i64::from(b)).collect::<Vec<_>>();
+    let mut hi = lo.clone();
+    for row in layer.rows() {
+        for ((lo, hi), &w) in lo.iter_mut().zip(&mut hi).zip(row) {
+            let max = i64::from(QA * QA) * i64::from(w);
+            if w < 0 {
+                *lo += max;
+            } else {
+                *hi += max;
+            }
+        }
+    }
+    lo.iter().chain(&hi).all(|&x| x.abs() <= DENSE_LIMIT)
+}
+
+impl Board {
+    /// Output of the quantized network, which is what search uses
+    pub fn net_output(&self) -> NetOutput {
+        let weights = weights();
+        let net = &weights.quantized;
+        let arch = &weights.float.arch;
+        let [stm, nstm] = net.ft.transform(self, &arch.inputs);
+        net.out([&stm[..arch.l1_size], &nstm[..arch.l1_size]], arch.output_bucket(self))
+    }
+
+    pub fn raw_eval(&self) -> f32 {
+        self.net_output().cp()
+    }
+
+    /// Evaluation with the unquantized network. Slower, but useful as a reference for the quantized one.
+    pub fn float_eval(&self) -> f32 {
+        let net = &weights().float;
+        let l1_size = net.arch.l1_size;
+        let [stm, nstm] = net.ft.transform(self, &net.arch.inputs);
+        net.out([&stm[..l1_size], &nstm[..l1_size]], net.arch.output_bucket(self))
+            .cp()
+    }
+
+    /// Evaluates the position from an accumulator that has already been updated for it
+    pub fn eval_accumulator(&self, acc: &Accumulator) -> NetOutput {
+        let weights = weights();
+        weights
+            .quantized
+            .out(acc.get(self.stm()), weights.float.arch.output_bucket(self))
+    }
+}
+
+#[cfg(test)]
+mod quantized_tests {
+    use super::QuantizedNetwork;
+    use crate::{
+        board::Board,
+        eval::{
+            embedded_network,
+            network::{Architecture, NetOutput, Network},
+            INPUT_LAYOUT,
+        },
+        historized_board::HistorizedBoard,
+    };
+
+    #[test]
+    fn quantized_close_to_float() {
+        let mut total_err = 0.;
+        let mut count = 0;
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        ] {
+            let mut board = HistorizedBoard::from(fen);
+            for ply in 0..30 {
+                let moves = board.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                board.make_move(moves[(ply * 5) % moves.len()]);
+
+                let float = board.board().float_eval();
+                let quantized = board.board().raw_eval();
+                let err = (float - quantized).abs();
+                // Individual positions can land on the wrong side of a clipping boundary, so only the average is
+                // held to a tight bound
+                assert!(
+                    err <= float.abs().mul_add(0.1, 50.),
+                    "quantized {quantized} too far from float {float} for {}",
+                    board.board().to_fen()
+                );
+                total_err += err;
+                count += 1;
+            }
+        }
+        let mean_err = total_err / count as f32;
+        assert!(mean_err <= 10., "mean quantization error of {mean_err}");
+    }
+
+    /// Catches an embedded network that can't be quantized before it ships, rather than when the engine starts
+    #[test]
+    fn embedded_network_quantizes() {
+        if let Err(err) = QuantizedNetwork::from_float(&embedded_network()) {
+            panic!("bins/raw.bin can't be embedded: {err}");
+        }
+    }
+
+    /// Clipped weights fit an i8 one at a time, but summed over every input they overflow an i32
+    #[test]
+    fn wide_layers_saturate() {
+        let arch = Architecture {
+            layers: vec![1],
+            l1_size: 512,
+            ..Architecture::embedded()
+        };
+        let mut floats = vec![0.01f32; arch.parameter_count()];
+        // Every accumulator starts out clipped, and every weight of the output layer is as large as it gets
+        let ft_bias = arch.input_size() * arch.l1_size;
+        floats[ft_bias..ft_bias + arch.l1_size].fill(1.);
+        floats[ft_bias + arch.l1_size..ft_bias + 3 * arch.l1_size].fill(1.98);
+        let net = QuantizedNetwork::from_float(&Network::from_floats(arch, &floats)).unwrap();
+        assert_eq!(net.wide, [true]);
+
+        let board = Board::default();
+        let [stm, nstm] = net.ft.transform(&board, &INPUT_LAYOUT);
+        let NetOutput::Cp(cp) = net.out([&stm[..512], &nstm[..512]], 0) else {
+            panic!("one output should make a scalar head");
+        };
+        // 1024 * 255² * 127 wraps around to a negative number in an i32
+        assert!(cp > 100_000., "{cp}");
+    }
+}
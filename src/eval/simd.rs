@@ -0,0 +1,302 @@
+use std::{fmt, sync::LazyLock};
+
+/// Which set of hand-written kernels the network runs on.
+///
+/// This is picked once from the features of the CPU we're running on rather than at compile time, so the same
+/// binary can be shipped to machines with and without AVX-512. Every kernel does the same integer arithmetic in a
+/// different order, and since integer addition wraps the same way regardless of order, they all give bit for bit
+/// identical results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    Avx2,
+    Avx512,
+}
+
+static KERNEL: LazyLock<Kernel> = LazyLock::new(Kernel::detect);
+
+impl Kernel {
+    fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512bw") {
+                return Self::Avx512;
+            }
+            if is_x86_feature_detected!("avx2") {
+                return Self::Avx2;
+            }
+        }
+        Self::Scalar
+    }
+
+    /// All kernels that can run on this machine
+    pub fn available() -> Vec<Self> {
+        let best = *KERNEL;
+        [Self::Scalar, Self::Avx2, Self::Avx512]
+            .into_iter()
+            .filter(|&k| k as u8 <= best as u8)
+            .collect()
+    }
+}
+
+impl fmt::Display for Kernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scalar => write!(f, "scalar"),
+            Self::Avx2 => write!(f, "avx2"),
+            Self::Avx512 => write!(f, "avx512"),
+        }
+    }
+}
+
+/// The kernel selected for this CPU
+pub fn kernel() -> Kernel {
+    *KERNEL
+}
+
+/// Adds every row in `adds` to `acc` and subtracts every row in `subs`
+pub fn add_sub(acc: &mut [i16], adds: &[&[i16]], subs: &[&[i16]]) {
+    add_sub_with(kernel(), acc, adds, subs);
+}
+
+/// `out += input * weights`, where `weights` holds one row of `out.len()` weights per input. Inputs that are zero
+/// are skipped, which matters for the first layer where most activations are clipped.
+pub fn affine(out: &mut [i32], input: &[i32], weights: &[i8]) {
+    affine_with(kernel(), out, input, weights);
+}
+
+fn add_sub_with(kernel: Kernel, acc: &mut [i16], adds: &[&[i16]], subs: &[&[i16]]) {
+    assert!(adds.iter().chain(subs).all(|row| row.len() == acc.len()));
+
+    match kernel {
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx512 if acc.len().is_multiple_of(avx512::I16_PER_LOOP) => unsafe { avx512::add_sub(acc, adds, subs) },
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 | Kernel::Avx512 if acc.len().is_multiple_of(avx2::I16_PER_LOOP) => unsafe {
+            avx2::add_sub(acc, adds, subs);
+        },
+        _ => scalar::add_sub(acc, adds, subs),
+    }
+}
+
+fn affine_with(kernel: Kernel, out: &mut [i32], input: &[i32], weights: &[i8]) {
+    assert_eq!(weights.len(), input.len() * out.len());
+
+    match kernel {
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx512 if out.len().is_multiple_of(16) => unsafe { avx512::affine(out, input, weights) },
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 | Kernel::Avx512 if out.len().is_multiple_of(8) => unsafe { avx2::affine(out, input, weights) },
+        _ => scalar::affine(out, input, weights),
+    }
+}
+
+mod scalar {
+    // Credit to akimbo. Working through the accumulator a block at a time lets the compiler keep the block in
+    // registers across all the rows instead of loading and storing it for every one.
+    const ELEMENTS_PER_LOOP: usize = 8 * 256 / 16;
+
+    pub fn add_sub(acc: &mut [i16], adds: &[&[i16]], subs: &[&[i16]]) {
+        let mut regs = [0i16; ELEMENTS_PER_LOOP];
+
+        for offset in (0..acc.len()).step_by(ELEMENTS_PER_LOOP) {
+            let len = ELEMENTS_PER_LOOP.min(acc.len() - offset);
+            let regs = &mut regs[..len];
+            regs.copy_from_slice(&acc[offset..offset + len]);
+
+            for add in adds {
+                for (reg, &w) in regs.iter_mut().zip(add[offset..].iter()) {
+                    *reg = reg.wrapping_add(w);
+                }
+            }
+
+            for sub in subs {
+                for (reg, &w) in regs.iter_mut().zip(sub[offset..].iter()) {
+                    *reg = reg.wrapping_sub(w);
+                }
+            }
+
+            acc[offset..offset + len].copy_from_slice(regs);
+        }
+    }
+
+    pub fn affine(out: &mut [i32], input: &[i32], weights: &[i8]) {
+        for (&i, row) in input.iter().zip(weights.chunks_exact(out.len())) {
+            if i == 0 {
+                continue;
+            }
+            for (o, &w) in out.iter_mut().zip(row) {
+                *o = o.wrapping_add(i.wrapping_mul(i32::from(w)));
+            }
+        }
+    }
+}
+
+// Every load and store is unaligned, so the pointer casts don't need the alignment of the vector type
+#[cfg(target_arch = "x86_64")]
+#[allow(clippy::cast_ptr_alignment)]
+mod avx2 {
+    use std::arch::x86_64::{
+        __m256i, _mm256_add_epi16, _mm256_add_epi32, _mm256_cvtepi8_epi32, _mm256_loadu_si256, _mm256_mullo_epi32,
+        _mm256_set1_epi32, _mm256_storeu_si256, _mm256_sub_epi16, _mm_loadl_epi64,
+    };
+
+    const REGISTERS: usize = 8;
+    const I16_PER_REGISTER: usize = 16;
+    pub const I16_PER_LOOP: usize = REGISTERS * I16_PER_REGISTER;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn add_sub(acc: &mut [i16], adds: &[&[i16]], subs: &[&[i16]]) {
+        for offset in (0..acc.len()).step_by(I16_PER_LOOP) {
+            let load = |src: &[i16], r: usize| unsafe {
+                _mm256_loadu_si256(src[offset + r * I16_PER_REGISTER..].as_ptr().cast::<__m256i>())
+            };
+
+            let mut regs: [__m256i; REGISTERS] = std::array::from_fn(|r| load(acc, r));
+            for add in adds {
+                for (r, reg) in regs.iter_mut().enumerate() {
+                    *reg = _mm256_add_epi16(*reg, load(add, r));
+                }
+            }
+            for sub in subs {
+                for (r, reg) in regs.iter_mut().enumerate() {
+                    *reg = _mm256_sub_epi16(*reg, load(sub, r));
+                }
+            }
+            for (r, reg) in regs.iter().enumerate() {
+                _mm256_storeu_si256(
+                    acc[offset + r * I16_PER_REGISTER..].as_mut_ptr().cast::<__m256i>(),
+                    *reg,
+                );
+            }
+        }
+    }
+
+    /// Requires `out.len()` to be a multiple of 8
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn affine(out: &mut [i32], input: &[i32], weights: &[i8]) {
+        for chunk in (0..out.len()).step_by(8) {
+            let mut sum = _mm256_loadu_si256(out[chunk..].as_ptr().cast::<__m256i>());
+            for (&i, row) in input.iter().zip(weights.chunks_exact(out.len())) {
+                if i == 0 {
+                    continue;
+                }
+                // Sign extend eight weights to i32 lanes
+                let w = _mm256_cvtepi8_epi32(_mm_loadl_epi64(row[chunk..].as_ptr().cast()));
+                sum = _mm256_add_epi32(sum, _mm256_mullo_epi32(w, _mm256_set1_epi32(i)));
+            }
+            _mm256_storeu_si256(out[chunk..].as_mut_ptr().cast::<__m256i>(), sum);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[allow(clippy::cast_ptr_alignment)]
+mod avx512 {
+    use std::arch::x86_64::{
+        __m512i, _mm512_add_epi16, _mm512_add_epi32, _mm512_cvtepi8_epi32, _mm512_loadu_si512, _mm512_mullo_epi32,
+        _mm512_set1_epi32, _mm512_storeu_si512, _mm512_sub_epi16, _mm_loadu_si128,
+    };
+
+    const REGISTERS: usize = 8;
+    const I16_PER_REGISTER: usize = 32;
+    pub const I16_PER_LOOP: usize = REGISTERS * I16_PER_REGISTER;
+
+    #[target_feature(enable = "avx512f,avx512bw")]
+    pub unsafe fn add_sub(acc: &mut [i16], adds: &[&[i16]], subs: &[&[i16]]) {
+        for offset in (0..acc.len()).step_by(I16_PER_LOOP) {
+            let load = |src: &[i16], r: usize| unsafe {
+                _mm512_loadu_si512(src[offset + r * I16_PER_REGISTER..].as_ptr().cast::<__m512i>())
+            };
+
+            let mut regs: [__m512i; REGISTERS] = std::array::from_fn(|r| load(acc, r));
+            for add in adds {
+                for (r, reg) in regs.iter_mut().enumerate() {
+                    *reg = _mm512_add_epi16(*reg, load(add, r));
+                }
+            }
+            for sub in subs {
+                for (r, reg) in regs.iter_mut().enumerate() {
+                    *reg = _mm512_sub_epi16(*reg, load(sub, r));
+                }
+            }
+            for (r, reg) in regs.iter().enumerate() {
+                _mm512_storeu_si512(
+                    acc[offset + r * I16_PER_REGISTER..].as_mut_ptr().cast::<__m512i>(),
+                    *reg,
+                );
+            }
+        }
+    }
+
+    /// Requires `out.len()` to be a multiple of 16
+    #[target_feature(enable = "avx512f,avx512bw")]
+    pub unsafe fn affine(out: &mut [i32], input: &[i32], weights: &[i8]) {
+        for chunk in (0..out.len()).step_by(16) {
+            let mut sum = _mm512_loadu_si512(out[chunk..].as_ptr().cast::<__m512i>());
+            for (&i, row) in input.iter().zip(weights.chunks_exact(out.len())) {
+                if i == 0 {
+                    continue;
+                }
+                // Sign extend sixteen weights to i32 lanes
+                let w = _mm512_cvtepi8_epi32(_mm_loadu_si128(row[chunk..].as_ptr().cast()));
+                sum = _mm512_add_epi32(sum, _mm512_mullo_epi32(w, _mm512_set1_epi32(i)));
+            }
+            _mm512_storeu_si512(out[chunk..].as_mut_ptr().cast::<__m512i>(), sum);
+        }
+    }
+}
+
+#[cfg(test)]
+mod simd_tests {
+    use super::{add_sub_with, affine_with, Kernel};
+
+    /// Deterministic junk so the tests don't need a rng
+    fn values(seed: u32, len: usize) -> impl Iterator<Item = i32> {
+        (0..len as u32).map(move |i| {
+            let x = (i ^ seed)
+                .wrapping_mul(0x9E37_79B9)
+                .rotate_left(13)
+                .wrapping_mul(0x85EB_CA6B);
+            (x >> 16) as i32
+        })
+    }
+
+    #[test]
+    fn add_sub_kernels_agree() {
+        for len in [768, 256, 40] {
+            let rows = (0..6)
+                .map(|r| values(r, len).map(|x| (x as i16) >> 4).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            let adds = rows[..4].iter().map(Vec::as_slice).collect::<Vec<_>>();
+            let subs = rows[4..].iter().map(Vec::as_slice).collect::<Vec<_>>();
+            let start = values(99, len).map(|x| x as i16).collect::<Vec<_>>();
+
+            let mut expected = start.clone();
+            add_sub_with(Kernel::Scalar, &mut expected, &adds, &subs);
+            for kernel in Kernel::available() {
+                let mut acc = start.clone();
+                add_sub_with(kernel, &mut acc, &adds, &subs);
+                assert_eq!(acc, expected, "{kernel}");
+            }
+        }
+    }
+
+    #[test]
+    fn affine_kernels_agree() {
+        for (m, n) in [(1536, 16), (16, 32), (32, 1), (12, 24)] {
+            // Include zeroes since the kernels skip them
+            let input = values(7, m).map(|x| (x % 300).max(0)).collect::<Vec<_>>();
+            let weights = values(3, m * n).map(|x| x as i8).collect::<Vec<_>>();
+            let start = values(5, n).collect::<Vec<_>>();
+
+            let mut expected = start.clone();
+            affine_with(Kernel::Scalar, &mut expected, &input, &weights);
+            for kernel in Kernel::available() {
+                let mut out = start.clone();
+                affine_with(kernel, &mut out, &input, &weights);
+                assert_eq!(out, expected, "{kernel}");
+            }
+        }
+    }
+}
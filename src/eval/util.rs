@@ -1,24 +1,22 @@
-use super::{L1_SIZE, NET};
+use super::{features::MAX_ACTIVE, network::Layer, simd};
+use arrayvec::ArrayVec;
 
 // Credit to akimbo. This function streamlines the assembly generated and prevents unnecessary
 // redundant loads and stores to the same simd vectors. Does sparse matmul.
-pub fn f32_update(acc: &mut [f32], adds: &[usize], subs: &[usize]) {
+pub(super) fn f32_update(ft: &Layer<f32>, acc: &mut [f32], adds: &[usize], subs: &[usize]) {
     const REGISTERS: usize = 8;
     const ELEMENTS_PER_LOOP: usize = REGISTERS * 256 / 32;
-    const _: () = assert!(L1_SIZE % ELEMENTS_PER_LOOP == 0);
-    assert_eq!(acc.len(), L1_SIZE);
+    assert_eq!(acc.len(), ft.outputs());
 
     let mut regs = [0f32; ELEMENTS_PER_LOOP];
 
-    for i in 0..L1_SIZE / ELEMENTS_PER_LOOP {
-        let offset = ELEMENTS_PER_LOOP * i;
-
-        for (reg, &j) in regs.iter_mut().zip(acc[offset..].iter()) {
-            *reg = j;
-        }
+    for offset in (0..acc.len()).step_by(ELEMENTS_PER_LOOP) {
+        let len = ELEMENTS_PER_LOOP.min(acc.len() - offset);
+        let regs = &mut regs[..len];
+        regs.copy_from_slice(&acc[offset..offset + len]);
 
         for &add in adds {
-            let weights = &NET.ft.weights[add];
+            let weights = ft.row(add);
 
             for (reg, &w) in regs.iter_mut().zip(weights[offset..].iter()) {
                 *reg += w;
@@ -26,50 +24,20 @@ pub fn f32_update(acc: &mut [f32], adds: &[usize], subs: &[usize]) {
         }
 
         for &sub in subs {
-            let weights = &NET.ft.weights[sub];
+            let weights = ft.row(sub);
 
             for (reg, &w) in regs.iter_mut().zip(weights[offset..].iter()) {
                 *reg -= w;
             }
         }
 
-        for (a, &r) in acc[offset..].iter_mut().zip(regs.iter()) {
-            *a = r;
-        }
+        acc[offset..offset + len].copy_from_slice(regs);
     }
 }
-//pub fn update(acc: &mut [i16], adds: &[usize], subs: &[usize]) {
-//assert_eq!(acc.len(), L1_SIZE);
-//const REGISTERS: usize = 8;
-//const ELEMENTS_PER_LOOP: usize = REGISTERS * 256 / 16;
-//
-//let mut regs = [0i16; ELEMENTS_PER_LOOP];
-//
-//for i in 0..L1_SIZE / ELEMENTS_PER_LOOP {
-//    let offset = ELEMENTS_PER_LOOP * i;
-//
-//    for (reg, &j) in regs.iter_mut().zip(acc[offset..].iter()) {
-//        *reg = j;
-//    }
-//
-//    for &add in adds {
-//        let weights = &NET.ft.weights[add];
-//
-//        for (reg, &w) in regs.iter_mut().zip(weights[offset..].iter()) {
-//            *reg += w;
-//        }
-//    }
-//
-//    for &sub in subs {
-//        let weights = &NET.ft.weights[sub];
-//
-//        for (reg, &w) in regs.iter_mut().zip(weights[offset..].iter()) {
-//            *reg -= w;
-//        }
-//    }
-//
-//    for (a, &r) in acc[offset..].iter_mut().zip(regs.iter()) {
-//        *a = r;
-//    }
-//}
-//}
+
+/// Integer version of [`f32_update`] for the quantized feature transformer
+pub(super) fn i16_update(ft: &Layer<i16>, acc: &mut [i16], adds: &[usize], subs: &[usize]) {
+    assert_eq!(acc.len(), ft.outputs());
+    let rows = |features: &[usize]| features.iter().map(|&f| ft.row(f)).collect::<ArrayVec<_, MAX_ACTIVE>>();
+    simd::add_sub(acc, &rows(adds), &rows(subs));
+}
@@ -0,0 +1,140 @@
+use crate::{arena::Arena, board::Board, chess_move::Move, types::pieces::PieceName};
+use std::io;
+
+/// Lets a user walk through the tree left over from the last search from the terminal. Children are listed
+/// from most to least visited, and the number next to each child is what should be typed to descend into it.
+pub fn explore(arena: &Arena) {
+    let Some((root, root_board)) = arena.last_search() else {
+        println!("No search tree to explore, run a search first");
+        return;
+    };
+
+    // Each entry is a node we descended into along with the position at that node
+    let mut path = vec![(root, "root".to_string(), arena.root_visits(), root_board.clone())];
+
+    loop {
+        let (ptr, _, visits, board) = path.last().unwrap();
+        let line = path.iter().skip(1).map(|(_, m, _, _)| m.as_str()).collect::<Vec<_>>();
+        println!("{}", board.board());
+        println!("Line: root {}", line.join(" "));
+        println!("Visits: {visits}  State: {:?}", arena[*ptr].game_state());
+        println!();
+
+        let mut edges = arena[*ptr].edges().iter().collect::<Vec<_>>();
+        edges.sort_by_key(|e| -e.visits());
+
+        println!("  #  move       visits        Q   policy    SEE");
+        for (i, edge) in edges.iter().enumerate() {
+            let q = if edge.visits() == 0 {
+                "       -".to_string()
+            } else {
+                format!("{:8.4}", edge.q())
+            };
+            println!(
+                "{i:>3}  {:<7} {:>9} {q} {:>8.4} {:>6}{}",
+                edge.m().to_string(),
+                edge.visits(),
+                edge.policy(),
+                see_value(board.board(), edge.m()),
+                if edge.child().is_some() { "" } else { "  (no node)" },
+            );
+        }
+        println!();
+        println!("Enter a child number to descend, u to go up, r to return to the root, q to quit");
+
+        let mut buffer = String::new();
+        if io::stdin().read_line(&mut buffer).unwrap() == 0 {
+            return;
+        }
+
+        match parse_command(&buffer) {
+            Command::Quit => return,
+            Command::Up => {
+                if path.len() > 1 {
+                    path.pop();
+                }
+            }
+            Command::Root => path.truncate(1),
+            Command::Child(i) => match edges.get(i) {
+                Some(edge) => {
+                    if let Some(child) = edge.child() {
+                        let mut board = board.clone();
+                        board.make_move(edge.m());
+                        path.push((child, edge.m().to_string(), edge.visits(), board));
+                    } else {
+                        println!("That child has no node in the tree");
+                    }
+                }
+                None => println!("Unrecognized input"),
+            },
+            Command::Unknown => println!("Unrecognized input"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Quit,
+    Up,
+    Root,
+    /// Index into the children as they're listed, most visited first
+    Child(usize),
+    Unknown,
+}
+
+fn parse_command(input: &str) -> Command {
+    match input.trim() {
+        "q" | "quit" => Command::Quit,
+        "u" | "up" => Command::Up,
+        "r" | "root" => Command::Root,
+        x => x.parse().map_or(Command::Unknown, Command::Child),
+    }
+}
+
+/// Finds the exact static exchange value of a move by searching over thresholds
+fn see_value(board: &Board, m: Move) -> i32 {
+    let mut lo = -PieceName::Queen.value() * 2;
+    let mut hi = PieceName::Queen.value() * 2;
+    while lo < hi {
+        let mid = (lo + hi + 1).div_euclid(2);
+        if board.see(m, mid) {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    lo
+}
+
+#[cfg(test)]
+mod explorer_tests {
+    use super::{parse_command, see_value, Command};
+    use crate::{board::Board, chess_move::Move};
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("q\n"), Command::Quit);
+        assert_eq!(parse_command("quit"), Command::Quit);
+        assert_eq!(parse_command(" u "), Command::Up);
+        assert_eq!(parse_command("root\n"), Command::Root);
+        assert_eq!(parse_command("12\n"), Command::Child(12));
+        assert_eq!(parse_command("-1"), Command::Unknown);
+        assert_eq!(parse_command("e2e4"), Command::Unknown);
+        assert_eq!(parse_command(""), Command::Unknown);
+    }
+
+    #[test]
+    fn finds_exact_see() {
+        let see = |fen: &str, m: &str| {
+            let board = Board::from_fen(fen);
+            see_value(&board, Move::from_san(m, &board))
+        };
+        // Free knight
+        assert_eq!(see("4k3/8/8/3n4/4P3/8/8/4K3 w - - 0 1", "e4d5"), 400);
+        // Knight for a defended pawn
+        assert_eq!(see("4k3/8/2p5/3p4/8/4N3/8/4K3 w - - 0 1", "e3d5"), 100 - 400);
+        // Rook takes a knight defended by a pawn, and the queen behind it recaptures
+        assert_eq!(see("4k3/8/4p3/3n4/8/8/3R4/3QK3 w - - 0 1", "d2d5"), 400 - 600 + 100);
+        assert_eq!(see("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", "e2e4"), 0);
+    }
+}
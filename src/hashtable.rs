@@ -1,11 +1,15 @@
 use std::mem::size_of;
 
+/// Draw chance is stored in fixed point so it fits in the padding next to the key, keeping entries at 8 bytes
 #[derive(Default, Debug, Clone, Copy)]
 pub struct TableEntry {
     key: u16,
+    draw: u16,
     eval: f32,
 }
 
+const _: () = assert!(size_of::<TableEntry>() == 8);
+
 #[derive(Debug)]
 pub struct HashTable {
     data: Box<[TableEntry]>,
@@ -19,12 +23,13 @@ impl HashTable {
         Self { data }
     }
 
-    pub fn probe(&self, hash: u64) -> Option<f32> {
+    /// Returns the stored expected score and draw chance of a position
+    pub fn probe(&self, hash: u64) -> Option<(f32, f32)> {
         let idx = self.index(hash);
         let key = hash as u16;
         let entry = &self.data[idx];
         if entry.key == key {
-            return Some(entry.eval);
+            return Some((entry.eval, f32::from(entry.draw) / f32::from(u16::MAX)));
         }
         None
     }
@@ -35,10 +40,11 @@ impl HashTable {
         }
     }
 
-    pub fn insert(&mut self, hash: u64, eval: f32) {
+    pub fn insert(&mut self, hash: u64, eval: f32, draw: f32) {
         let idx = self.index(hash);
         let key = hash as u16;
-        self.data[idx] = TableEntry { key, eval }
+        let draw = (draw * f32::from(u16::MAX)).round() as u16;
+        self.data[idx] = TableEntry { key, draw, eval }
     }
 
     fn index(&self, hash: u64) -> usize {
@@ -1,6 +1,7 @@
 use crate::{
     board::Board,
     chess_move::Move,
+    eval::{accumulator::Accumulator, network::NetOutput},
     movegen::MoveList,
     node::GameState,
     types::pieces::{Color, Piece, PieceName},
@@ -10,6 +11,7 @@ use crate::{
 pub struct HistorizedBoard {
     board: Board,
     hashes: Vec<u64>,
+    accumulator: Accumulator,
 }
 
 impl HistorizedBoard {
@@ -58,16 +60,30 @@ impl HistorizedBoard {
         self.board.hash()
     }
 
-    pub fn wdl(&self) -> f32 {
-        self.board.wdl()
+    pub fn wdl(&mut self) -> f32 {
+        let (w, d, _) = self.wdl_probabilities();
+        w + d / 2.
     }
 
-    pub fn scaled_eval(&self) -> i32 {
-        self.board.scaled_eval()
+    pub fn wdl_probabilities(&mut self) -> (f32, f32, f32) {
+        let output = self.net_output();
+        self.board.output_wdl(output)
     }
 
-    pub fn raw_eval(&self) -> f32 {
-        self.board.raw_eval()
+    pub fn scaled_eval(&mut self) -> i32 {
+        let raw = self.raw_eval();
+        self.board.scale_eval(raw)
+    }
+
+    pub fn raw_eval(&mut self) -> f32 {
+        self.net_output().cp()
+    }
+
+    /// Evaluates the position using the accumulator carried along with the board, so only the features that
+    /// changed since the last evaluation need to be applied
+    pub fn net_output(&mut self) -> NetOutput {
+        self.accumulator.update(&self.board);
+        self.board.eval_accumulator(&self.accumulator)
     }
 
     pub const fn stm(&self) -> Color {
@@ -88,6 +104,7 @@ impl From<&str> for HistorizedBoard {
         Self {
             board: Board::from_fen(value),
             hashes: Vec::with_capacity(128),
+            accumulator: Accumulator::default(),
         }
     }
 }
@@ -15,23 +15,33 @@ mod arena;
 mod attack_boards;
 mod bench;
 pub mod board;
+mod calibrate;
 pub mod chess_move;
+mod datagen;
 mod edge;
 pub mod eval;
+mod explorer;
 mod game_time;
 mod hashtable;
 mod historized_board;
 mod magics;
+mod mine;
 pub mod movegen;
 mod node;
 mod perft;
+mod pgn;
 pub mod policy;
 mod search_type;
 pub mod see;
+mod tune_policy;
 pub mod types;
 mod uci;
 mod value;
 mod zobrist;
 
 pub use crate::bench::bench;
+pub use crate::calibrate::calibrate;
+pub use crate::datagen::{datagen, format_visits, parse_visits, to_bulletformat, RECORD_SIZE};
+pub use crate::mine::mine;
+pub use crate::tune_policy::tune_policy;
 pub use uci::main_loop;
@@ -31,6 +31,15 @@ impl Default for Rng {
 }
 
 impl Rng {
+    /// Xorshift gets stuck on a state of zero, so that seed starts from the default state instead
+    pub fn with_seed(seed: u64) -> Self {
+        if seed == 0 {
+            Self::default()
+        } else {
+            Self(seed)
+        }
+    }
+
     pub fn next_u64(&mut self) -> u64 {
         self.0 ^= self.0 << 13;
         self.0 ^= self.0 >> 7;
@@ -1,8 +1,42 @@
 use std::env;
 
 fn main() {
-    if env::args().any(|x| x == *"bench") {
+    let mut args = env::args().collect::<Vec<_>>();
+    if let Some(idx) = args.iter().position(|x| x == "--evalfile") {
+        let Some(path) = args.get(idx + 1).cloned() else {
+            println!("Usage: --evalfile <network file>");
+            return;
+        };
+        if let Err(err) = imm_cee_tee_ess::eval::loader::load_network(&path) {
+            println!("Failed to load {path}: {err}");
+            return;
+        }
+        args.drain(idx..idx + 2);
+    }
+    if let Some(idx) = args.iter().position(|x| x == "--policyfile") {
+        let Some(path) = args.get(idx + 1).cloned() else {
+            println!("Usage: --policyfile <policy file>");
+            return;
+        };
+        if let Err(err) = imm_cee_tee_ess::policy::network::load_policy(&path) {
+            println!("Failed to load {path}: {err}");
+            return;
+        }
+        args.drain(idx..idx + 2);
+    }
+
+    if args.iter().any(|x| x == "bench") {
         imm_cee_tee_ess::bench();
+    } else if args.get(1).is_some_and(|x| x == "calibrate") {
+        imm_cee_tee_ess::calibrate(&args[2..]);
+    } else if args.get(1).is_some_and(|x| x == "datagen") {
+        imm_cee_tee_ess::datagen(&args[2..]);
+    } else if args.get(1).is_some_and(|x| x == "mine") {
+        imm_cee_tee_ess::mine(&args[2..]);
+    } else if args.get(1).is_some_and(|x| x == "tunepolicy") {
+        imm_cee_tee_ess::tune_policy(&args[2..]);
+    } else if args.get(1).is_some_and(|x| x == "pack") {
+        imm_cee_tee_ess::eval::loader::pack(&args[2..]);
     } else {
         imm_cee_tee_ess::main_loop();
     }
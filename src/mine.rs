@@ -0,0 +1,170 @@
+use crate::{
+    arena::Arena, board::Board, historized_board::HistorizedBoard, pgn::pgn_positions, search_type::SearchType,
+};
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::atomic::AtomicBool,
+    thread,
+};
+
+const USAGE: &str = "Usage: mine <positions file or pgn> <output file> [--nodes n] [--threshold x] [--skip n]";
+const DEFAULT_NODES: u64 = 5000;
+/// How far apart the search and static expected scores must be for a position to be flagged
+const DEFAULT_THRESHOLD: f32 = 0.25;
+/// Opening plies of every PGN game that are left out, since they're the same from game to game
+const DEFAULT_SKIP: usize = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Settings {
+    input: String,
+    output: String,
+    nodes: u64,
+    threshold: f32,
+    skip: usize,
+}
+
+/// Searches a set of positions for a fixed number of nodes and flags the ones where the search disagrees with the
+/// network's own [`Board::wdl_probabilities`] about the expected score.
+///
+/// These make good training data and regression cases for the network. The input is either a PGN file, read by its
+/// `.pgn` extension, or one FEN or EPD per line. Flagged positions are written as EPD, with the expected scores for
+/// the side to move in the `c0` and `c1` operations:
+///
+/// `<fen> c0 "search 0.823"; c1 "static 0.412";`
+///
+/// Usage: mine <positions file or pgn> <output file> [--nodes n] [--threshold x] [--skip n]
+pub fn mine(args: &[String]) {
+    let Some(settings) = parse_args(args) else {
+        println!("{USAGE}");
+        return;
+    };
+
+    let is_pgn = Path::new(&settings.input)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pgn"));
+    let fens = if is_pgn {
+        let text = fs::read_to_string(&settings.input).expect("PGN file not found");
+        let mut seen = HashSet::new();
+        pgn_positions(&text)
+            .into_iter()
+            .flat_map(|game| game.into_iter().skip(settings.skip))
+            .filter(|board| seen.insert(board.hash()))
+            .map(Board::to_fen)
+            .collect()
+    } else {
+        BufReader::new(File::open(&settings.input).expect("Positions file not found"))
+            .lines()
+            .map(Result::unwrap)
+            .filter_map(|line| epd_fen(&line))
+            .collect::<Vec<_>>()
+    };
+    println!("Searching {} positions for {} nodes each", fens.len(), settings.nodes);
+
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let chunk_size = fens.len().div_ceil(threads).max(1);
+    let flagged = thread::scope(|s| {
+        let mut handles = Vec::new();
+        for chunk in fens.chunks(chunk_size) {
+            let settings = &settings;
+            handles.push(s.spawn(move || mine_chunk(chunk, settings)));
+        }
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    let mut output = BufWriter::new(File::create(&settings.output).expect("Could not create output file"));
+    for line in &flagged {
+        writeln!(output, "{line}").unwrap();
+    }
+    output.flush().unwrap();
+    println!(
+        "Flagged {} of {} positions, written to {}",
+        flagged.len(),
+        fens.len(),
+        settings.output
+    );
+}
+
+fn parse_args(args: &[String]) -> Option<Settings> {
+    let [input, output, flags @ ..] = args else {
+        return None;
+    };
+    let mut settings = Settings {
+        input: input.clone(),
+        output: output.clone(),
+        nodes: DEFAULT_NODES,
+        threshold: DEFAULT_THRESHOLD,
+        skip: DEFAULT_SKIP,
+    };
+    for pair in flags.chunks(2) {
+        let [key, value] = pair else {
+            return None;
+        };
+        match key.as_str() {
+            "--nodes" => settings.nodes = value.parse().ok()?,
+            "--threshold" => settings.threshold = value.parse().ok()?,
+            "--skip" => settings.skip = value.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some(settings)
+}
+
+/// The FEN at the start of a line, which may be an EPD with operations after it or a FEN with a score after it
+fn epd_fen(line: &str) -> Option<String> {
+    let fields = line.split(['|', ';']).next()?.split_whitespace().collect::<Vec<_>>();
+    if fields.len() < 4 {
+        return None;
+    }
+    // Move counters are optional in EPD, and operations take their place
+    let counters = fields
+        .iter()
+        .skip(4)
+        .take(2)
+        .take_while(|f| f.parse::<u32>().is_ok())
+        .count();
+    Some(fields[..4 + counters].join(" "))
+}
+
+fn mine_chunk(fens: &[String], settings: &Settings) -> Vec<String> {
+    let mut arena = Arena::default();
+    let halt = AtomicBool::new(false);
+    let mut flagged = Vec::new();
+    for fen in fens {
+        let board = HistorizedBoard::from(fen.as_str());
+        if board.legal_moves().is_empty() {
+            continue;
+        }
+        arena.reset();
+        arena.start_search(&board, &halt, SearchType::Nodes(settings.nodes), false);
+        let search = arena.best_score();
+        let (win, draw, _) = board.board().wdl_probabilities();
+        let eval = win + draw / 2.;
+
+        if (search - eval).abs() > settings.threshold {
+            let epd = fen.split_whitespace().take(4).collect::<Vec<_>>().join(" ");
+            flagged.push(format!("{epd} c0 \"search {search:.3}\"; c1 \"static {eval:.3}\";"));
+        }
+    }
+    flagged
+}
+
+#[cfg(test)]
+mod mine_tests {
+    use super::epd_fen;
+
+    #[test]
+    fn reads_fens_out_of_lines() {
+        let fen = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+        assert_eq!(epd_fen(fen).as_deref(), Some(fen));
+        assert_eq!(epd_fen(&format!("{fen} | 25 | 0.5")).as_deref(), Some(fen));
+        assert_eq!(
+            epd_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - bm e2a6; id \"kiwipete\";")
+                .as_deref(),
+            Some("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq -")
+        );
+        assert_eq!(epd_fen(""), None);
+    }
+}
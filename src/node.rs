@@ -12,11 +12,13 @@ pub enum GameState {
 const _: () = assert!(size_of::<GameState>() == size_of::<Option<GameState>>());
 
 impl GameState {
-    const fn evaluate(self) -> Option<f32> {
+    /// Expected score and draw chance of a finished game. `draw_score` is what a draw is worth to the side to
+    /// move, which is only 0.5 when contempt is disabled.
+    const fn evaluate(self, draw_score: f32) -> Option<(f32, f32)> {
         match self {
-            Self::Won => Some(1.),
-            Self::Draw => Some(0.5),
-            Self::Lost => Some(0.),
+            Self::Won => Some((1., 0.)),
+            Self::Draw => Some((draw_score, 1.)),
+            Self::Lost => Some((0., 0.)),
             Self::Ongoing => None,
         }
     }
@@ -53,8 +55,8 @@ impl Node {
         self.game_state.is_terminal()
     }
 
-    pub const fn evaluate(&self) -> Option<f32> {
-        self.game_state.evaluate()
+    pub const fn evaluate(&self, draw_score: f32) -> Option<(f32, f32)> {
+        self.game_state.evaluate(draw_score)
     }
 
     pub fn should_expand(&self) -> bool {
@@ -103,6 +105,10 @@ impl Node {
         self.edge_idx = u8::MAX;
     }
 
+    pub const fn game_state(&self) -> GameState {
+        self.game_state
+    }
+
     pub fn set_game_state(&mut self, game_state: GameState) {
         self.game_state = game_state;
     }
@@ -0,0 +1,223 @@
+use crate::{
+    board::{fen::STARTING_FEN, Board},
+    chess_move::Move,
+    types::{pieces::PieceName, square::SQUARE_NAMES},
+};
+
+/// Every position of every game in a PGN file, starting position included, in the order they were played.
+///
+/// Comments, variations and annotations are skipped. A game with a move that can't be read is cut off before it.
+pub fn pgn_positions(text: &str) -> Vec<Vec<Board>> {
+    let mut games = Vec::new();
+    let mut game: Option<Vec<Board>> = None;
+    let mut start = STARTING_FEN.to_string();
+    // Set once a game has a move that can't be read, so the rest of it is ignored
+    let mut broken = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => chars.by_ref().take_while(|&c| c != '}').for_each(drop),
+            ';' => chars.by_ref().take_while(|&c| c != '\n').for_each(drop),
+            '(' => {
+                let mut depth = 1;
+                while depth > 0 {
+                    match chars.next() {
+                        Some('(') => depth += 1,
+                        Some(')') => depth -= 1,
+                        Some('{') => chars.by_ref().take_while(|&c| c != '}').for_each(drop),
+                        Some(_) => (),
+                        None => break,
+                    }
+                }
+            }
+            '[' => {
+                let tag = chars.by_ref().take_while(|&c| c != ']').collect::<String>();
+                // Tags belong to the next game, so a game without a result ends here
+                games.extend(game.take());
+                broken = false;
+                if let Some(("FEN", value)) = tag.split_once(char::is_whitespace).map(|(k, v)| (k, v.trim())) {
+                    start = value.trim_matches('"').to_string();
+                }
+            }
+            c if c.is_whitespace() => (),
+            _ => {
+                let mut token = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "{};()[".contains(c) {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+
+                if matches!(token.as_str(), "1-0" | "0-1" | "1/2-1/2" | "*") {
+                    games.extend(game.take());
+                    start = STARTING_FEN.to_string();
+                    broken = false;
+                    continue;
+                }
+                // Strip a move number such as `12.` or `12...`, which may be glued to the move. Only digits followed
+                // by dots count, since castling written as `0-0` starts with a digit too.
+                let unnumbered = token.trim_start_matches(|c: char| c.is_ascii_digit());
+                let san = if unnumbered.len() < token.len() && unnumbered.starts_with('.') {
+                    unnumbered.trim_start_matches('.')
+                } else {
+                    token.as_str()
+                };
+                if san.is_empty() || san.starts_with('$') || broken {
+                    continue;
+                }
+                let positions = game.get_or_insert_with(|| vec![Board::from_fen(&start)]);
+                let board = *positions.last().unwrap();
+                match san_to_move(&board, san) {
+                    Some(m) => {
+                        let mut next = board;
+                        next.make_move(m);
+                        positions.push(next);
+                    }
+                    None => broken = true,
+                }
+            }
+        }
+    }
+    games.extend(game);
+    games
+}
+
+/// Finds the legal move a move in Standard Algebraic Notation refers to, such as `Nbd7`, `exd8=Q+` or `O-O`
+pub fn san_to_move(board: &Board, san: &str) -> Option<Move> {
+    let san = san.trim_end_matches(['+', '#', '!', '?']);
+    let moves = board.legal_moves();
+    let castle = match san {
+        "O-O" | "0-0" => Some('g'),
+        "O-O-O" | "0-0-0" => Some('c'),
+        _ => None,
+    };
+    if let Some(file) = castle {
+        return moves
+            .into_iter()
+            .find(|m| m.is_castle() && SQUARE_NAMES[m.to()].starts_with(file));
+    }
+
+    let (san, promotion) = match san.split_once('=') {
+        Some((san, promo)) => (san, Some(piece_from_char(promo.chars().next()?)?)),
+        None => match san.chars().last() {
+            // Promotions are sometimes written without the `=`, as in `e8Q`
+            Some(c) if c.is_ascii_uppercase() => (&san[..san.len() - 1], Some(piece_from_char(c)?)),
+            _ => (san, None),
+        },
+    };
+    let (piece, san) = match san.chars().next() {
+        Some(c) if c.is_ascii_uppercase() => (piece_from_char(c)?, &san[1..]),
+        _ => (PieceName::Pawn, san),
+    };
+    let san = san.replace('x', "");
+    if san.len() < 2 {
+        return None;
+    }
+    let (from, to) = san.split_at(san.len() - 2);
+
+    let mut candidates = moves.into_iter().filter(|m| {
+        m.piece_moving(board).name() == piece
+            && SQUARE_NAMES[m.to()] == to
+            && m.promotion() == promotion
+            && from.chars().all(|c| SQUARE_NAMES[m.from()].contains(c))
+    });
+    let m = candidates.next()?;
+    candidates.next().is_none().then_some(m)
+}
+
+const fn piece_from_char(c: char) -> Option<PieceName> {
+    match c {
+        'N' => Some(PieceName::Knight),
+        'B' => Some(PieceName::Bishop),
+        'R' => Some(PieceName::Rook),
+        'Q' => Some(PieceName::Queen),
+        'K' => Some(PieceName::King),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod pgn_tests {
+    use super::{pgn_positions, san_to_move};
+    use crate::board::Board;
+
+    #[test]
+    fn reads_san() {
+        let board = Board::from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+        for (san, uci) in [
+            ("O-O", "e1g1"),
+            ("O-O-O", "e1c1"),
+            ("Nxf7", "e5f7"),
+            ("dxe6", "d5e6"),
+            ("Qxh3", "f3h3"),
+            ("Bxa6", "e2a6"),
+            ("Rb1", "a1b1"),
+            ("Nb1", "c3b1"),
+            ("gxh3+", "g2h3"),
+        ] {
+            assert_eq!(
+                san_to_move(&board, san).map(|m| m.to_string()),
+                Some(uci.to_string()),
+                "{san}"
+            );
+        }
+        // Two knights can reach d1, so it needs disambiguating
+        let board = Board::from_fen("4k3/8/8/8/8/8/1N3N2/4K3 w - - 0 1");
+        assert_eq!(san_to_move(&board, "Nd1"), None);
+        assert_eq!(
+            san_to_move(&board, "Nfd1").map(|m| m.to_string()),
+            Some("f2d1".to_string())
+        );
+
+        let board = Board::from_fen("8/1P2k3/8/8/8/8/8/4K3 w - - 0 1");
+        assert_eq!(
+            san_to_move(&board, "b8=N").map(|m| m.to_string()),
+            Some("b7b8n".to_string())
+        );
+        assert_eq!(
+            san_to_move(&board, "b8Q").map(|m| m.to_string()),
+            Some("b7b8q".to_string())
+        );
+    }
+
+    #[test]
+    fn reads_games() {
+        let pgn = r#"[Event "Test"]
+[Result "1-0"]
+
+1. e4 {best by test} e5 2. Nf3 (2. f4 exf4 (2... d5)) 2... Nc6 $1 3. Bb5 a6; a comment
+4. Ba4 1-0
+
+[Event "From a position"]
+[FEN "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"]
+
+1. e4 Kd7 2. Qh5 Kc7 *
+"#;
+        let games = pgn_positions(pgn);
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].len(), 8);
+        assert!(games[0]
+            .last()
+            .unwrap()
+            .to_fen()
+            .starts_with("r1bqkbnr/1ppp1ppp/p1n5/4p3/B3P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 1"));
+        // The second game has no queen, so it stops before the illegal move
+        assert_eq!(games[1].len(), 3);
+    }
+
+    #[test]
+    fn reads_castling_with_zeros() {
+        let pgn = "1.e4 e5 2.Nf3 Nf6 3.Bc4 Bc5 4.0-0 0-0 5.d3 1/2-1/2";
+        let games = pgn_positions(pgn);
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].len(), 10);
+        assert!(games[0]
+            .last()
+            .unwrap()
+            .to_fen()
+            .starts_with("rnbq1rk1/pppp1ppp/5n2/2b1p3/2B1P3/3P1N2/PPP2PPP/RNBQ1RK1 b - -"));
+    }
+}
@@ -1,25 +1,63 @@
+pub mod network;
+mod weights;
+
 use crate::{
+    attack_boards::pawn_set_attacks,
     board::Board,
     chess_move::Move,
     historized_board::HistorizedBoard,
     movegen::MAX_MOVES,
-    types::pieces::{Color, PieceName},
+    types::{
+        bitboard::Bitboard,
+        pieces::{Color, PieceName},
+    },
 };
 use arrayvec::ArrayVec;
 use std::cmp::min;
 
+pub use weights::WEIGHTS;
+
 type PolicyVec = ArrayVec<(Move, f32), { MAX_MOVES }>;
+
+/// Number of terms in the hand-crafted policy, see [`Board::policy_features`]
+pub const POLICY_FEATURES: usize = 9;
+/// What each term of the hand-crafted policy measures, in the order of [`WEIGHTS`]
+pub const FEATURE_NAMES: [&str; POLICY_FEATURES] = [
+    "see >= -100",
+    "see >= 1",
+    "pst delta",
+    "gives check",
+    "escapes threat",
+    "into pawn attack",
+    "queen promo",
+    "underpromo",
+    "castle",
+];
+
+/// Everything the hand-crafted policy needs to know about a position, computed once for all of its moves
+pub(crate) struct PolicyContext {
+    game_phase: i32,
+    /// Squares the opponent attacks
+    threats: Bitboard,
+    /// Squares the opponent's pawns attack
+    pawn_threats: Bitboard,
+}
+
 impl Board {
+    /// Prior of every legal move, from the policy network when one is loaded
     pub fn policies(&self) -> PolicyVec {
-        let game_phase = self.game_phase();
+        network::loaded_policy().map_or_else(|| self.handcrafted_policies(), |net| net.policies(self))
+    }
+
+    pub fn handcrafted_policies(&self) -> PolicyVec {
+        let ctx = self.policy_context();
         let mut policies = PolicyVec::new_const();
         let mut denom = 0.0;
 
         // Softmax
         let legal_moves = self.legal_moves();
         legal_moves.iter().for_each(|m| {
-            // Bet you've never seen hand crafted policy before :)
-            let pol = f32::from(self.see(*m, -100)) + f32::from(self.see(*m, 1)) + self.move_pestimate(*m, game_phase);
+            let pol = policy_logit(&self.policy_features(*m, &ctx), &WEIGHTS);
             policies.push((*m, pol));
             denom += pol.exp();
         });
@@ -27,7 +65,33 @@ impl Board {
         policies
     }
 
-    fn game_phase(&self) -> i32 {
+    pub(crate) fn policy_context(&self) -> PolicyContext {
+        PolicyContext {
+            game_phase: self.game_phase(),
+            threats: self.threats(!self.stm()),
+            pawn_threats: pawn_set_attacks(self.piece_color(!self.stm(), PieceName::Pawn), !self.stm()),
+        }
+    }
+
+    /// Terms of the hand-crafted policy for a move, which are weighted by [`WEIGHTS`] and summed into its logit
+    pub(crate) fn policy_features(&self, m: Move, ctx: &PolicyContext) -> [f32; POLICY_FEATURES] {
+        let escapes = ctx.threats.contains(m.from()) && !ctx.threats.contains(m.to()) && !m.is_castle();
+
+        // Bet you've never seen hand crafted policy before :)
+        [
+            f32::from(self.see(m, -100)),
+            f32::from(self.see(m, 1)),
+            self.move_pestimate(m, ctx.game_phase),
+            f32::from(self.gives_check(m)),
+            f32::from(escapes),
+            f32::from(ctx.pawn_threats.contains(m.to())),
+            f32::from(m.promotion() == Some(PieceName::Queen)),
+            f32::from(m.promotion().is_some_and(|p| p != PieceName::Queen)),
+            f32::from(m.is_castle()),
+        ]
+    }
+
+    pub(crate) fn game_phase(&self) -> i32 {
         let mut game_phase = 0;
 
         for piece in PieceName::iter() {
@@ -66,10 +130,14 @@ impl Board {
         let mg_phase = min(game_phase, 24);
         let eg_phase = 24 - mg_phase;
 
-        ((mg_pts * mg_phase + eg_pts * eg_phase) / 24) as f32 / 400.
+        ((mg_pts * mg_phase + eg_pts * eg_phase) / 24) as f32
     }
 }
 
+pub(crate) fn policy_logit(features: &[f32; POLICY_FEATURES], weights: &[f32; POLICY_FEATURES]) -> f32 {
+    features.iter().zip(weights).map(|(f, w)| f * w).sum()
+}
+
 impl HistorizedBoard {
     pub fn policies(&self) -> PolicyVec {
         self.board().policies()
@@ -253,3 +321,56 @@ fn game_phase_value(piece: PieceName) -> i32 {
         PieceName::None => unimplemented!(),
     }
 }
+
+#[cfg(test)]
+mod policy_tests {
+    use super::{policy_logit, POLICY_FEATURES, WEIGHTS};
+    use crate::board::Board;
+
+    fn features(fen: &str, mv: &str) -> [f32; POLICY_FEATURES] {
+        let board = Board::from_fen(fen);
+        let m = board.legal_moves().into_iter().find(|m| m.to_string() == mv).unwrap();
+        board.policy_features(m, &board.policy_context())
+    }
+
+    #[test]
+    fn tactical_terms() {
+        let promo = "4k3/P7/8/8/8/8/8/R3K3 w Q - 0 1";
+        assert_eq!(features(promo, "a7a8q")[3..], [1., 0., 0., 1., 0., 0.]);
+        assert_eq!(features(promo, "a7a8n")[3..], [0., 0., 0., 0., 1., 0.]);
+        assert_eq!(features(promo, "e1c1")[3..], [0., 0., 0., 0., 0., 1.]);
+
+        // The knight on e4 is attacked by the pawn on d5, and d4 is covered by the pawn on c5
+        let knight = "4k3/8/8/2pp4/4N3/8/8/4K3 w - - 0 1";
+        assert_eq!(features(knight, "e4f6")[3..6], [1., 1., 0.]);
+        assert_eq!(features(knight, "e4c5")[3..6], [0., 1., 0.]);
+        let board = Board::from_fen("4k3/8/8/2p5/8/5N2/8/4K3 w - - 0 1");
+        let policies = board.handcrafted_policies();
+        let prior = |mv: &str| policies.iter().find(|(m, _)| m.to_string() == mv).unwrap().1;
+        assert!(prior("f3d4") < prior("f3d2"));
+    }
+
+    /// With the newer terms switched off, the weights give the same logits as the policy that only had SEE and
+    /// piece square tables
+    #[test]
+    fn first_terms_match_old_policy() {
+        let mut weights = [0.; POLICY_FEATURES];
+        weights[..3].copy_from_slice(&WEIGHTS[..3]);
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 0 1",
+        ] {
+            let board = Board::from_fen(fen);
+            let ctx = board.policy_context();
+            for m in board.legal_moves() {
+                let old = f32::from(board.see(m, -100))
+                    + f32::from(board.see(m, 1))
+                    + board.move_pestimate(m, board.game_phase()) / 400.;
+                let new = policy_logit(&board.policy_features(m, &ctx), &weights);
+                assert!((old - new).abs() < 1e-6, "{fen} {m}");
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use super::PolicyVec;
+use crate::{
+    board::Board,
+    chess_move::Move,
+    eval::loader::{checksum, NetworkError},
+    types::pieces::Color,
+};
+use arrayvec::ArrayVec;
+use std::{
+    fs, ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// First bytes of every policy file
+pub const POLICY_MAGIC: [u8; 4] = *b"ICTP";
+/// Bumped whenever the layout of the header or the weights that follow it changes
+pub const POLICY_VERSION: u32 = 1;
+/// One input per piece type, color and square, with the side to move's pieces first and at the bottom of the board
+pub const POLICY_INPUTS: usize = 768;
+/// Moves are scored through one slot for the piece and square they come from, and one for the piece and square they
+/// go to. Promotions go to the slot of the piece they promote to.
+pub const MOVE_SLOTS: usize = 768;
+/// Hidden layer size of networks the trainer produces by default
+pub const DEFAULT_HIDDEN: usize = 32;
+/// Anything wider than this is far more likely to be a corrupted header than a real network
+pub(crate) const MAX_HIDDEN: usize = 4096;
+
+/// Policy loaded at runtime, or null to use the hand-crafted policy
+static LOADED: AtomicPtr<PolicyNetwork> = AtomicPtr::new(ptr::null_mut());
+
+/// A small network that scores moves.
+///
+/// The position is run through a single hidden layer, and the logit of a move is the dot product of that layer with
+/// the rows of both of the move's slots, plus their biases.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyNetwork {
+    pub hidden: usize,
+    /// One row of `hidden` weights per input
+    pub ft_weights: Vec<f32>,
+    pub ft_bias: Vec<f32>,
+    /// One row of `hidden` weights per move slot
+    pub move_weights: Vec<f32>,
+    pub move_bias: Vec<f32>,
+}
+
+impl PolicyNetwork {
+    pub fn zeroed(hidden: usize) -> Self {
+        Self {
+            hidden,
+            ft_weights: vec![0.; POLICY_INPUTS * hidden],
+            ft_bias: vec![0.; hidden],
+            move_weights: vec![0.; MOVE_SLOTS * hidden],
+            move_bias: vec![0.; MOVE_SLOTS],
+        }
+    }
+
+    pub const fn parameter_count(hidden: usize) -> usize {
+        POLICY_INPUTS * hidden + hidden + MOVE_SLOTS * hidden + MOVE_SLOTS
+    }
+
+    /// Reads floats in the order [`PolicyNetwork::to_floats`] writes them
+    pub fn from_floats(hidden: usize, floats: &[f32]) -> Self {
+        assert_eq!(
+            floats.len(),
+            Self::parameter_count(hidden),
+            "Wrong number of policy weights"
+        );
+        let (ft_weights, rest) = floats.split_at(POLICY_INPUTS * hidden);
+        let (ft_bias, rest) = rest.split_at(hidden);
+        let (move_weights, move_bias) = rest.split_at(MOVE_SLOTS * hidden);
+        Self {
+            hidden,
+            ft_weights: ft_weights.to_vec(),
+            ft_bias: ft_bias.to_vec(),
+            move_weights: move_weights.to_vec(),
+            move_bias: move_bias.to_vec(),
+        }
+    }
+
+    /// Weights followed by biases, hidden layer first
+    pub fn to_floats(&self) -> Vec<f32> {
+        [&self.ft_weights[..], &self.ft_bias, &self.move_weights, &self.move_bias].concat()
+    }
+
+    /// Hidden layer before its activation, which the trainer needs for backpropagation
+    pub fn hidden_sums(&self, features: &[usize]) -> Vec<f32> {
+        let mut sums = self.ft_bias.clone();
+        for &feat in features {
+            let row = &self.ft_weights[feat * self.hidden..(feat + 1) * self.hidden];
+            for (s, w) in sums.iter_mut().zip(row) {
+                *s += w;
+            }
+        }
+        sums
+    }
+
+    /// Logit of a move given the activated hidden layer
+    pub fn logit(&self, hidden: &[f32], slots: [usize; 2]) -> f32 {
+        slots
+            .iter()
+            .map(|&slot| {
+                let row = &self.move_weights[slot * self.hidden..(slot + 1) * self.hidden];
+                self.move_bias[slot] + hidden.iter().zip(row).map(|(h, w)| h * w).sum::<f32>()
+            })
+            .sum()
+    }
+
+    /// Softmax over the logits of every legal move
+    pub fn policies(&self, board: &Board) -> PolicyVec {
+        let hidden = self
+            .hidden_sums(&input_features(board))
+            .into_iter()
+            .map(activate)
+            .collect::<Vec<_>>();
+        let mut policies = board
+            .legal_moves()
+            .into_iter()
+            .map(|m| (m, self.logit(&hidden, move_slots(board, m))))
+            .collect::<PolicyVec>();
+
+        let max = policies
+            .iter()
+            .map(|&(_, logit)| logit)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let mut denom = 0.;
+        for (_, pol) in &mut policies {
+            *pol = (*pol - max).exp();
+            denom += *pol;
+        }
+        policies.iter_mut().for_each(|(_, pol)| *pol /= denom);
+        policies
+    }
+}
+
+pub const fn activate(x: f32) -> f32 {
+    x.clamp(0., 1.)
+}
+
+/// Whether a hidden neuron passes gradients through, see [`activate`]
+pub fn activation_gradient(x: f32) -> f32 {
+    if x > 0. && x < 1. {
+        1.
+    } else {
+        0.
+    }
+}
+
+/// Active inputs of a position, seen from the side to move
+pub fn input_features(board: &Board) -> ArrayVec<usize, 32> {
+    let flip = if board.stm() == Color::White { 0 } else { 56 };
+    board
+        .occupancies()
+        .into_iter()
+        .map(|sq| {
+            let piece = board.piece_at(sq);
+            384 * usize::from(piece.color() != board.stm()) + 64 * usize::from(piece.name()) + usize::from(sq.0 ^ flip)
+        })
+        .collect()
+}
+
+/// The from and to slots a move is scored through, seen from the side to move
+pub fn move_slots(board: &Board, m: Move) -> [usize; 2] {
+    let flip = if board.stm() == Color::White { 0 } else { 56 };
+    let piece = m.piece_moving(board).name();
+    let lands_as = m.promotion().unwrap_or(piece);
+    [
+        64 * usize::from(piece) + usize::from(m.from().0 ^ flip),
+        384 + 64 * usize::from(lands_as) + usize::from(m.to().0 ^ flip),
+    ]
+}
+
+/// The policy network the engine is currently using, if any
+pub fn loaded_policy() -> Option<&'static PolicyNetwork> {
+    let loaded = LOADED.load(Ordering::Acquire);
+    if loaded.is_null() {
+        None
+    } else {
+        Some(unsafe { &*loaded })
+    }
+}
+
+/// Switches the engine over to the policy network in `path`
+///
+/// # Errors
+/// Same as [`read_policy`]. The engine falls back to the hand-crafted policy.
+pub fn load_policy(path: &str) -> Result<(), NetworkError> {
+    let net = read_policy(path);
+    // Leaked for the same reason value networks are, see `eval::loader::load_network`
+    let ptr = net
+        .as_ref()
+        .map_or(ptr::null_mut(), |net| ptr::from_mut(Box::leak(Box::new(net.clone()))));
+    LOADED.store(ptr, Ordering::Release);
+    net.map(|_| ())
+}
+
+/// Goes back to the hand-crafted policy
+pub fn use_handcrafted_policy() {
+    LOADED.store(ptr::null_mut(), Ordering::Release);
+}
+
+/// Reads a policy file: [`POLICY_MAGIC`], then the version, the hidden layer size, and the checksum of the weights,
+/// followed by the weights as little endian f32s.
+///
+/// # Errors
+/// If the file can't be read or its header doesn't match its weights
+pub fn read_policy(path: &str) -> Result<PolicyNetwork, NetworkError> {
+    let bytes = fs::read(path)?;
+    if bytes.len() < POLICY_MAGIC.len() || bytes[..POLICY_MAGIC.len()] != POLICY_MAGIC {
+        return Err(NetworkError::BadMagic);
+    }
+    let header = bytes.get(4..20).ok_or(NetworkError::Truncated)?;
+    let version = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    if version != POLICY_VERSION {
+        return Err(NetworkError::UnsupportedVersion(version));
+    }
+    let hidden = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let expected_checksum = u64::from_le_bytes(header[8..16].try_into().unwrap());
+
+    if hidden == 0 || hidden > MAX_HIDDEN {
+        return Err(NetworkError::PolicyHidden(hidden));
+    }
+
+    let weights = &bytes[20..];
+    let expected = 4 * PolicyNetwork::parameter_count(hidden);
+    if weights.len() != expected {
+        return Err(NetworkError::Size {
+            expected,
+            found: weights.len(),
+        });
+    }
+    let found = checksum(weights);
+    if expected_checksum != found {
+        return Err(NetworkError::Checksum {
+            expected: expected_checksum,
+            found,
+        });
+    }
+
+    let floats = weights
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    Ok(PolicyNetwork::from_floats(hidden, &floats))
+}
+
+/// Writes a policy file in the format [`read_policy`] reads
+///
+/// # Errors
+/// If the file can't be written
+pub fn write_policy(net: &PolicyNetwork, path: &str) -> Result<(), NetworkError> {
+    let weights = net.to_floats().iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+    let mut bytes = POLICY_MAGIC.to_vec();
+    bytes.extend_from_slice(&POLICY_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(net.hidden as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&weights).to_le_bytes());
+    bytes.extend_from_slice(&weights);
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod policy_network_tests {
+    use super::{move_slots, read_policy, write_policy, PolicyNetwork, MAX_HIDDEN, MOVE_SLOTS};
+    use crate::{board::Board, eval::loader::NetworkError};
+
+    #[test]
+    fn file_round_trip() {
+        let mut net = PolicyNetwork::zeroed(4);
+        for (i, w) in net.move_weights.iter_mut().enumerate() {
+            *w = (i % 13) as f32 / 7. - 1.;
+        }
+        net.ft_bias = vec![0.25, 0.5, 0.75, 1.];
+
+        let path = std::env::temp_dir().join("policy_round_trip.bin");
+        let path = path.to_str().unwrap();
+        write_policy(&net, path).unwrap();
+        assert_eq!(read_policy(path).unwrap(), net);
+
+        let mut bytes = std::fs::read(path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(path, bytes).unwrap();
+        assert!(read_policy(path).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_hidden_layer() {
+        let path = std::env::temp_dir().join("policy_oversized.bin");
+        let path = path.to_str().unwrap();
+        write_policy(&PolicyNetwork::zeroed(4), path).unwrap();
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[8..12].copy_from_slice(&(MAX_HIDDEN as u32 + 1).to_le_bytes());
+        std::fs::write(path, bytes).unwrap();
+        assert!(matches!(read_policy(path), Err(NetworkError::PolicyHidden(h)) if h == MAX_HIDDEN + 1));
+    }
+
+    #[test]
+    fn policies_sum_to_one() {
+        let mut net = PolicyNetwork::zeroed(4);
+        net.ft_bias = vec![0.5; 4];
+        for (i, w) in net.move_weights.iter_mut().enumerate() {
+            *w = ((i * 7) % 11) as f32 / 11.;
+        }
+        let board = Board::from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+        let policies = net.policies(&board);
+        assert_eq!(policies.len(), 48);
+        assert!((policies.iter().map(|(_, p)| p).sum::<f32>() - 1.).abs() < 1e-5);
+
+        // Slots are relative to the side to move, so mirrored moves share them
+        let flipped = Board::from_fen("r3k2r/pppbbppp/2n2q1P/1P2p3/3pn3/BN2PNP1/P1PPQPB1/R3K2R b KQkq - 0 1");
+        let mut slots = board
+            .legal_moves()
+            .iter()
+            .map(|&m| move_slots(&board, m))
+            .collect::<Vec<_>>();
+        let mut flipped_slots = flipped
+            .legal_moves()
+            .iter()
+            .map(|&m| move_slots(&flipped, m))
+            .collect::<Vec<_>>();
+        slots.sort_unstable();
+        flipped_slots.sort_unstable();
+        assert_eq!(slots, flipped_slots);
+        assert!(slots.iter().flatten().all(|&s| s < MOVE_SLOTS));
+    }
+}
@@ -0,0 +1,3 @@
+// Hand-picked defaults, the first three are the weights of the policy before the other terms were added. Running
+// `imm-cee-tee-ess tunepolicy` replaces this file with tuned weights.
+pub const WEIGHTS: [f32; 9] = [1.0, 1.0, 0.0025, 0.5, 0.8, -1.0, 1.5, -2.0, 0.5];
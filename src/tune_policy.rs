@@ -0,0 +1,272 @@
+use crate::{
+    arena::Arena,
+    board::Board,
+    chess_move::Move,
+    datagen::parse_visits,
+    historized_board::HistorizedBoard,
+    policy::{FEATURE_NAMES, POLICY_FEATURES, WEIGHTS},
+    search_type::SearchType,
+};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    sync::atomic::AtomicBool,
+    thread,
+};
+
+const DEFAULT_OUTPUT: &str = "src/policy/weights.rs";
+const DEFAULT_NODES: u64 = 1000;
+/// Keeps the Hessian invertible when a term never shows up in the data
+const RIDGE: f64 = 1e-6;
+
+/// Terms of every move of a position, along with the share of root visits each move got
+type Sample = Vec<([f64; POLICY_FEATURES], f64)>;
+
+/// Fits the weights of the hand-crafted policy to the root visit distributions of searches.
+///
+/// Each line of the input is either `fen | move:visits ...` as written by `datagen --policy`, or just a fen, which
+/// is searched for `nodes` nodes to get its distribution. The weights are fit by minimizing the cross entropy between
+/// the policy and the distributions, and written to a rust file that replaces `src/policy/weights.rs`.
+///
+/// Usage: tunepolicy <data file> [output file] [nodes]
+pub fn tune_policy(args: &[String]) {
+    let Some(path) = args.first() else {
+        println!("Usage: tunepolicy <data file> [output file] [nodes]");
+        return;
+    };
+    let output = args.get(1).map_or(DEFAULT_OUTPUT, String::as_str);
+    let nodes = args
+        .get(2)
+        .map_or(DEFAULT_NODES, |n| n.parse().expect("Invalid node count"));
+
+    let lines = BufReader::new(File::open(path).expect("Data file not found"))
+        .lines()
+        .map(Result::unwrap)
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>();
+    println!("Collecting visit distributions of {} positions", lines.len());
+
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let chunk_size = lines.len().div_ceil(threads).max(1);
+    let samples = thread::scope(|s| {
+        let mut handles = Vec::new();
+        for chunk in lines.chunks(chunk_size) {
+            handles.push(s.spawn(move || collect_samples(chunk, nodes)));
+        }
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect::<Vec<_>>()
+    });
+    assert!(!samples.is_empty(), "No usable positions to tune on");
+
+    let current = WEIGHTS.map(f64::from);
+    println!(
+        "{} positions, current loss {:.5}",
+        samples.len(),
+        loss(&samples, &current)
+    );
+    let fitted = fit(&samples, current);
+    println!("fitted loss {:.5}", loss(&samples, &fitted));
+    for ((name, old), new) in FEATURE_NAMES.iter().zip(current).zip(fitted) {
+        println!("{name:>16}: {old:>10.5} -> {new:>10.5}");
+    }
+
+    let weights = fitted.map(|w| w as f32);
+    let str = format!(
+        "// Generated by `imm-cee-tee-ess tunepolicy`. Rerun the tuner instead of editing these by hand.\n\
+         pub const WEIGHTS: [f32; {POLICY_FEATURES}] = {weights:?};\n"
+    );
+    fs::write(output, str).unwrap();
+    println!("Wrote weights to {output}");
+}
+
+fn collect_samples(lines: &[String], nodes: u64) -> Vec<Sample> {
+    let mut arena = Arena::default();
+    let halt = AtomicBool::new(false);
+    lines
+        .iter()
+        .filter_map(|line| {
+            let (board, visits) = if line.contains('|') {
+                parse_visits(line)?
+            } else {
+                let board = HistorizedBoard::from(line.trim());
+                if board.legal_moves().len() < 2 {
+                    return None;
+                }
+                arena.reset();
+                arena.start_search(&board, &halt, SearchType::Nodes(nodes), false);
+                (*board.board(), arena.root_distribution())
+            };
+            sample(&board, &visits)
+        })
+        .collect()
+}
+
+fn sample(board: &Board, visits: &[(Move, i32)]) -> Option<Sample> {
+    let total = visits.iter().map(|&(_, n)| n).sum::<i32>();
+    if visits.len() < 2 || total <= 0 {
+        return None;
+    }
+    let ctx = board.policy_context();
+    Some(
+        visits
+            .iter()
+            .map(|&(m, n)| {
+                let features = board.policy_features(m, &ctx).map(f64::from);
+                (features, f64::from(n) / f64::from(total))
+            })
+            .collect(),
+    )
+}
+
+/// Softmax of the weighted terms of every move in a sample
+fn probabilities(sample: &Sample, weights: &[f64; POLICY_FEATURES]) -> Vec<f64> {
+    let logits = sample
+        .iter()
+        .map(|(f, _)| f.iter().zip(weights).map(|(f, w)| f * w).sum::<f64>())
+        .collect::<Vec<_>>();
+    let max = logits.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let exps = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+    let denom = exps.iter().sum::<f64>();
+    exps.iter().map(|e| e / denom).collect()
+}
+
+/// Average cross entropy between the visit distributions and the policy
+fn loss(samples: &[Sample], weights: &[f64; POLICY_FEATURES]) -> f64 {
+    let total = samples
+        .iter()
+        .map(|sample| {
+            let probs = probabilities(sample, weights);
+            -sample
+                .iter()
+                .zip(probs)
+                .map(|((_, t), p)| t * p.max(1e-12).ln())
+                .sum::<f64>()
+        })
+        .sum::<f64>();
+    total / samples.len() as f64
+}
+
+/// Newton's method with a backtracking line search. The loss is convex in the weights and there are only a handful
+/// of them, so this converges in a few dozen steps no matter how differently the terms are scaled.
+fn fit(samples: &[Sample], mut weights: [f64; POLICY_FEATURES]) -> [f64; POLICY_FEATURES] {
+    let mut current = loss(samples, &weights);
+    for _ in 0..100 {
+        let mut grad = [0.; POLICY_FEATURES];
+        let mut hessian = [[0.; POLICY_FEATURES]; POLICY_FEATURES];
+        for sample in samples {
+            let probs = probabilities(sample, &weights);
+            let mut mean = [0.; POLICY_FEATURES];
+            for ((f, t), p) in sample.iter().zip(&probs) {
+                for i in 0..POLICY_FEATURES {
+                    grad[i] += (p - t) * f[i];
+                    mean[i] += p * f[i];
+                    for j in 0..POLICY_FEATURES {
+                        hessian[i][j] += p * f[i] * f[j];
+                    }
+                }
+            }
+            for i in 0..POLICY_FEATURES {
+                for j in 0..POLICY_FEATURES {
+                    hessian[i][j] -= mean[i] * mean[j];
+                }
+            }
+        }
+        for (i, row) in hessian.iter_mut().enumerate() {
+            row[i] += RIDGE * samples.len() as f64;
+        }
+
+        let step = solve(hessian, grad);
+        let slope = -grad.iter().zip(step).map(|(g, s)| g * s).sum::<f64>() / samples.len() as f64;
+        let mut t = 1.;
+        loop {
+            let mut candidate = weights;
+            for (w, s) in candidate.iter_mut().zip(step) {
+                *w -= t * s;
+            }
+            let new = loss(samples, &candidate);
+            if new <= 1e-4f64.mul_add(t * slope, current) {
+                weights = candidate;
+                if current - new < 1e-9 {
+                    return weights;
+                }
+                current = new;
+                break;
+            }
+            t /= 2.;
+            if t < 1e-6 {
+                return weights;
+            }
+        }
+    }
+    weights
+}
+
+/// Solves `a * x = b` by Gaussian elimination with partial pivoting
+fn solve(mut a: [[f64; POLICY_FEATURES]; POLICY_FEATURES], mut b: [f64; POLICY_FEATURES]) -> [f64; POLICY_FEATURES] {
+    for col in 0..POLICY_FEATURES {
+        let pivot = (col..POLICY_FEATURES)
+            .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+            .unwrap();
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in 0..POLICY_FEATURES {
+            if row != col {
+                let factor = a[row][col] / a[col][col];
+                let pivot_row = a[col];
+                for (x, p) in a[row].iter_mut().zip(pivot_row).skip(col) {
+                    *x = (-factor).mul_add(p, *x);
+                }
+                b[row] = (-factor).mul_add(b[col], b[row]);
+            }
+        }
+    }
+    std::array::from_fn(|i| b[i] / a[i][i])
+}
+
+#[cfg(test)]
+mod tune_policy_tests {
+    use super::{fit, loss, probabilities, Sample};
+    use crate::policy::POLICY_FEATURES;
+
+    #[test]
+    fn fit_recovers_weights() {
+        let truth: [f64; POLICY_FEATURES] = std::array::from_fn(|i| [0.7, 1.6, 0.004, -0.5, 2.][i % 5]);
+        let mut state = 12345u64;
+        let mut next = || {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (state >> 33) as f64 / f64::from(1u32 << 31)
+        };
+
+        let samples = (0..500)
+            .map(|i| {
+                let mut sample: Sample = (0..5 + i % 20)
+                    .map(|_| {
+                        let f = std::array::from_fn(|j| {
+                            if j == 2 {
+                                next().mul_add(400., -200.)
+                            } else {
+                                (next() > 0.5).into()
+                            }
+                        });
+                        (f, 0.)
+                    })
+                    .collect();
+                let targets = probabilities(&sample, &truth);
+                for ((_, target), p) in sample.iter_mut().zip(targets) {
+                    *target = p;
+                }
+                sample
+            })
+            .collect::<Vec<_>>();
+
+        let fitted = fit(&samples, [0.; POLICY_FEATURES]);
+        assert!(loss(&samples, &fitted) <= loss(&samples, &truth) + 1e-6);
+        // Within 1% of the true weight, or 0.001 for weights under 0.1
+        for (i, (weight, expected)) in fitted.iter().zip(truth).enumerate() {
+            let tolerance = 1e-2 * expected.abs().max(0.1);
+            assert!(
+                (weight - expected).abs() < tolerance,
+                "weight {i}: fitted {weight}, true {expected}, tolerance {tolerance}"
+            );
+        }
+    }
+}
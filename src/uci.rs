@@ -1,3 +1,4 @@
+use std::fs;
 use std::process::exit;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::{io, time::Duration};
@@ -6,9 +7,15 @@ use crate::arena::Arena;
 use crate::bench::bench;
 use crate::board::fen::{parse_fen_from_buffer, STARTING_FEN};
 use crate::chess_move::Move;
+use crate::eval::{
+    loader::{load_network, use_embedded_network},
+    simd,
+};
+use crate::explorer::explore;
 use crate::game_time::Clock;
 use crate::historized_board::HistorizedBoard;
 use crate::perft::perft;
+use crate::policy::network::{load_policy, use_handcrafted_policy};
 use crate::search_type::SearchType;
 use crate::{board::Board, types::pieces::Color};
 use std::thread;
@@ -17,6 +24,7 @@ const ENGINE_NAME: &str = "IM CEE TEE ESS";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub static PRETTY_PRINT: AtomicBool = AtomicBool::new(true);
+pub static SHOW_WDL: AtomicBool = AtomicBool::new(false);
 
 /// Main loop that handles UCI communication with GUIs
 pub fn main_loop() -> ! {
@@ -50,10 +58,11 @@ pub fn main_loop() -> ! {
                 arena.reset();
             }
             "eval" => println!(
-                "Raw eval: {:.3}\nEval post scaling: {} cp\nWDL: {:.5}",
+                "Raw eval: {:.3}\nEval post scaling: {} cp\nWDL: {:.5}\nKernel: {}",
                 board.raw_eval(),
                 board.scaled_eval(),
                 board.wdl(),
+                simd::kernel(),
             ),
             "position" => board = position_command(&input),
             "d" => {
@@ -63,6 +72,8 @@ pub fn main_loop() -> ! {
                 }
             }
             "bench" => bench(),
+            "tree" => export_tree(&arena, &input),
+            "explore" => explore(&arena),
             "go" => handle_go(&mut arena, &input, &board, &mut msg, &halt),
             "perft" => {
                 perft(board.board(), input[1].parse().unwrap());
@@ -75,9 +86,28 @@ pub fn main_loop() -> ! {
                 PRETTY_PRINT.store(false, Ordering::SeqCst);
             }
             "setoption" => match input[..] {
-                ["setoption", "name", "Hash", "value", x] => arena = Arena::new(x.parse().unwrap()),
+                ["setoption", "name", "Hash", "value", x] => {
+                    let contempt = arena.contempt();
+                    arena = Arena::new(x.parse().unwrap());
+                    arena.set_contempt(contempt);
+                }
                 ["setoption", "name", "Clear", "Hash", _x] => arena.reset(),
                 ["setoption", "name", "Threads", "value", _x] => (),
+                ["setoption", "name", "UCI_ShowWDL", "value", x] => SHOW_WDL.store(x == "true", Ordering::Relaxed),
+                ["setoption", "name", "Contempt", "value", x] => match x.parse::<i32>() {
+                    Ok(contempt) => arena.set_contempt(contempt.clamp(-1000, 1000)),
+                    Err(_) => println!("info string Invalid Contempt value {x}, expected an integer"),
+                },
+                ["setoption", "name", "EvalFile", "value", ref path @ ..] => {
+                    set_eval_file(&path.join(" "));
+                    // Cached evals came from the previous network
+                    arena.reset();
+                }
+                ["setoption", "name", "PolicyFile", "value", ref path @ ..] => {
+                    set_policy_file(&path.join(" "));
+                    // Expanded nodes hold priors from the previous policy
+                    arena.reset();
+                }
                 _ => println!("Option not recognized"),
             },
             _ => (),
@@ -85,11 +115,72 @@ pub fn main_loop() -> ! {
     }
 }
 
+/// Usage: tree <dot|json> [max depth] [min visits] [output file]
+fn export_tree(arena: &Arena, input: &[&str]) {
+    const USAGE: &str = "Usage: tree <dot|json> [max depth] [min visits] [output file]";
+    let (Ok(max_depth), Ok(min_visits)) = (
+        input.get(2).map_or(Ok(3), |x| x.parse()),
+        input.get(3).map_or(Ok(1), |x| x.parse()),
+    ) else {
+        println!("{USAGE}");
+        return;
+    };
+    let str = match input.get(1) {
+        Some(&"dot") => arena.export_dot(max_depth, min_visits),
+        Some(&"json") => arena.export_json(max_depth, min_visits),
+        _ => {
+            println!("{USAGE}");
+            return;
+        }
+    };
+
+    match input.get(4) {
+        Some(path) => {
+            if let Err(err) = fs::write(path, str) {
+                println!("Could not write {path}: {err}");
+            }
+        }
+        None => print!("{str}"),
+    }
+}
+
+/// `EvalFile` value that selects the network embedded in the binary
+const EMBEDDED_NET: &str = "<embedded>";
+
+fn set_eval_file(path: &str) {
+    if path.is_empty() || path == EMBEDDED_NET {
+        use_embedded_network();
+        return;
+    }
+    match load_network(path) {
+        Ok(()) => println!("info string Loaded network {path}"),
+        Err(err) => println!("info string Failed to load {path}: {err}. Keeping the current network"),
+    }
+}
+
+/// `PolicyFile` value that selects the hand-crafted policy
+const HANDCRAFTED_POLICY: &str = "<handcrafted>";
+
+fn set_policy_file(path: &str) {
+    if path.is_empty() || path == HANDCRAFTED_POLICY {
+        use_handcrafted_policy();
+        return;
+    }
+    match load_policy(path) {
+        Ok(()) => println!("info string Loaded policy {path}"),
+        Err(err) => println!("info string Failed to load {path}: {err}. Using the hand-crafted policy"),
+    }
+}
+
 fn uci_opts() {
     println!("id name {ENGINE_NAME} {VERSION}");
     println!("id author {}", env!("CARGO_PKG_AUTHORS"));
     println!("option name Threads type spin default 1 min 1 max 1");
     println!("option name Hash type spin default 32 min 1 max 16384");
+    println!("option name UCI_ShowWDL type check default false");
+    println!("option name Contempt type spin default 0 min -1000 max 1000");
+    println!("option name EvalFile type string default {EMBEDDED_NET}");
+    println!("option name PolicyFile type string default {HANDCRAFTED_POLICY}");
     println!("uciok");
 }
 
@@ -1,10 +1,157 @@
-use crate::board::Board;
+mod wdl_model;
+
+use crate::{board::Board, eval::network::NetOutput, types::pieces::PieceName};
+use wdl_model::{AS, BS};
 
 pub const SCALE: f32 = 400.;
 
+/// Material is clamped to this range before being fed to the win rate model
+const MIN_MATERIAL: i32 = 17;
+pub const MAX_MATERIAL: i32 = 78;
+/// Material is divided by this before evaluating the model polynomials, which keeps their coefficients sane
+const MATERIAL_NORM: f32 = 58.;
+
+/// Logistic model of game outcome given an evaluation, with parameters that depend on the material left on the
+/// board. `a` is the eval at which a win becomes as likely as not, and `b` controls how quickly the win rate
+/// rises around that point. Parameters are fit by `calibrate`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WinRateModel {
+    a: f32,
+    b: f32,
+}
+
+impl WinRateModel {
+    pub fn new(material: i32) -> Self {
+        let m = normalize_material(material);
+        Self::from_params(eval_poly(AS, m), eval_poly(BS, m))
+    }
+
+    pub const fn from_params(a: f32, b: f32) -> Self {
+        Self { a, b: b.max(1.) }
+    }
+
+    pub const fn a(self) -> f32 {
+        self.a
+    }
+
+    pub const fn b(self) -> f32 {
+        self.b
+    }
+
+    /// Win, draw, and loss chances for the side an eval of `cp` belongs to
+    pub fn wdl(self, cp: f32) -> (f32, f32, f32) {
+        let win = sigmoid((cp - self.a) / self.b);
+        let loss = sigmoid((-cp - self.a) / self.b);
+        (win, (1. - win - loss).max(0.), loss)
+    }
+
+    pub fn expected_score(self, cp: f32) -> f32 {
+        let (w, d, _) = self.wdl(cp);
+        w + d / 2.
+    }
+
+    /// Inverse of [`WinRateModel::expected_score`]. The model has no closed form inverse, but it is monotonic
+    /// so a bisection gets there quickly enough for uci output.
+    pub fn cp(self, score: f32) -> f32 {
+        let mut lo = -20_000.;
+        let mut hi = 20_000.;
+        for _ in 0..40 {
+            let mid = f32::midpoint(lo, hi);
+            if self.expected_score(mid) < score {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        f32::midpoint(lo, hi)
+    }
+
+    /// Rescales an eval so that +100 means the same win chance (50%) no matter the game phase. A model without a
+    /// draw margin has no such point, so its evals are left as they are.
+    pub fn normalize(self, cp: f32) -> i32 {
+        if self.a <= 0. {
+            return cp as i32;
+        }
+        (100. * cp / self.a.max(1.)) as i32
+    }
+}
+
 impl Board {
-    /// Uses a sigmoid to scale an integer evaluation from 0.0 to 1.0 using a sigmoid
+    /// Material count used by the win rate model. Pawns are 1, minors are 3, rooks 5, and queens 9.
+    pub fn material(&self) -> i32 {
+        [
+            (PieceName::Pawn, 1),
+            (PieceName::Knight, 3),
+            (PieceName::Bishop, 3),
+            (PieceName::Rook, 5),
+            (PieceName::Queen, 9),
+        ]
+        .into_iter()
+        .map(|(p, v)| v * self.piece(p).count_bits())
+        .sum()
+    }
+
+    pub fn win_rate_model(&self) -> WinRateModel {
+        WinRateModel::new(self.material())
+    }
+
+    /// Win, draw, and loss chances for the side to move. Networks with a WDL head predict them directly, otherwise
+    /// they come from the calibrated win rate model.
+    pub fn wdl_probabilities(&self) -> (f32, f32, f32) {
+        self.output_wdl(self.net_output())
+    }
+
+    /// Win, draw, and loss chances for the side to move given the network's output for this position
+    pub fn output_wdl(&self, output: NetOutput) -> (f32, f32, f32) {
+        match output {
+            NetOutput::Cp(raw) => self.win_rate_model().wdl(self.scale_eval(raw) as f32),
+            NetOutput::Wdl(wdl) => wdl.into(),
+        }
+    }
+
+    /// Expected score of the position from 0.0 to 1.0 according to the calibrated win rate model
     pub fn wdl(&self) -> f32 {
-        1.0 / (1.0 + (-self.scaled_eval() as f32 / SCALE).exp())
+        let (w, d, _) = self.wdl_probabilities();
+        w + d / 2.
+    }
+}
+
+/// Maps a material count to the input of the model polynomials
+pub fn normalize_material(material: i32) -> f32 {
+    material.clamp(MIN_MATERIAL, MAX_MATERIAL) as f32 / MATERIAL_NORM
+}
+
+pub fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+fn eval_poly(coeffs: [f32; 4], x: f32) -> f32 {
+    coeffs.iter().fold(0., |acc, &c| acc.mul_add(x, c))
+}
+
+#[cfg(test)]
+mod value_tests {
+    use super::WinRateModel;
+
+    #[test]
+    fn default_model_has_draws() {
+        for material in [0, 17, 40, 78, 100] {
+            let model = WinRateModel::new(material);
+            assert!((model.expected_score(0.) - 0.5).abs() < 1e-6);
+            assert!(model.wdl(0.).1 > 0.1, "{material}");
+
+            // Draws get rarer the further the eval is from equal, in either direction
+            let mut last = model.wdl(0.).1;
+            for cp in [50., 100., 250., 500., 1000., 2000.] {
+                let (win, draw, loss) = model.wdl(cp);
+                assert!(draw < last, "{material} {cp}");
+                assert!((model.wdl(-cp).1 - draw).abs() < 1e-6);
+                assert!(win > loss);
+                assert!((model.expected_score(cp) + model.expected_score(-cp) - 1.).abs() < 1e-6);
+                last = draw;
+            }
+            assert!(last < 0.01);
+            assert_eq!(model.normalize(250.), 250);
+        }
     }
 }
@@ -0,0 +1,4 @@
+// Defaults with a flat 100cp draw margin and the usual 400cp slope, until `imm-cee-tee-ess calibrate` is run on
+// real games to replace them.
+pub const AS: [f32; 4] = [0.0, 0.0, 0.0, 100.0];
+pub const BS: [f32; 4] = [0.0, 0.0, 0.0, 400.0];
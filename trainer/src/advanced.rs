@@ -1,14 +1,39 @@
-use crate::threat_inputs::ThreatInput;
-use bullet::{loader, lr, optimiser, wdl, LocalSettings, TrainingSchedule, TrainingSteps};
+use crate::{
+    config::{Config, LrSchedule, WdlSchedule},
+    data::{WdlPreparer, WeightedLoader},
+    export::{export, CHECK_FENS},
+    threat_inputs::ThreatInput,
+    validation::Validation,
+};
 use bullet::{
+    format::ChessBoard,
     operations::{self, sparse_affine_dual_with_activation},
     optimiser::AdamWOptimiser,
-    outputs, Activation, ExecutionContext, Graph, GraphBuilder, Node, QuantTarget, Shape, Trainer,
+    outputs::OutputBuckets,
+    Activation, ExecutionContext, Graph, GraphBuilder, Node, QuantTarget, Shape, Trainer,
+};
+use bullet::{lr, optimiser, wdl, LocalSettings, TrainingSchedule, TrainingSteps};
+
+use imm_cee_tee_ess::eval::{
+    network::{Architecture, NetOutput},
+    output_bucket,
+    quantized::{QA, QB, QO},
+    INPUT_SIZE, L1_SIZE, OUTPUT_BUCKETS,
 };
 
-use imm_cee_tee_ess::eval::{INPUT_SIZE, L1_SIZE};
+/// Picks the head of the last layer by piece count, exactly like the engine does
+#[derive(Clone, Copy, Default)]
+pub struct MaterialBuckets;
+
+impl OutputBuckets<ChessBoard> for MaterialBuckets {
+    const BUCKETS: usize = OUTPUT_BUCKETS;
+
+    fn bucket(&self, pos: &ChessBoard) -> u8 {
+        output_bucket(pos.occ().count_ones(), OUTPUT_BUCKETS) as u8
+    }
+}
 
-pub fn train() {
+pub fn train(config: &Config) {
     let (mut graph, output_node) = build_network();
 
     seed_weights(&mut graph);
@@ -24,100 +49,230 @@ pub fn train() {
             max_weight: 1.98,
         },
         ThreatInput,
-        outputs::Single,
-        vec![
-            ("ftw".to_string(), QuantTarget::Float),
-            ("ftb".to_string(), QuantTarget::Float),
-            ("l1w".to_string(), QuantTarget::Float),
-            ("l1b".to_string(), QuantTarget::Float),
-            ("l2w".to_string(), QuantTarget::Float),
-            ("l2b".to_string(), QuantTarget::Float),
-            ("l3w".to_string(), QuantTarget::Float),
-            ("l3b".to_string(), QuantTarget::Float),
-        ],
+        MaterialBuckets,
+        quant_targets(),
     );
 
-    let schedule = TrainingSchedule {
-        net_id: "threats".to_string(),
+    if let Some(checkpoint) = &config.resume {
+        trainer.load_from_checkpoint(checkpoint);
+    }
+
+    let wdl_head = Architecture::embedded().wdl_head();
+    let mut schedule = TrainingSchedule {
+        net_id: config.net_id.clone(),
         eval_scale: 400.0,
         steps: TrainingSteps {
-            batch_size: 16_384,
-            batches_per_superbatch: 6104,
-            start_superbatch: 0,
-            end_superbatch: 750,
+            batch_size: config.batch_size,
+            batches_per_superbatch: config.batches_per_superbatch,
+            start_superbatch: config.start_superbatch.unwrap_or(0),
+            end_superbatch: config.superbatches,
         },
-        wdl_scheduler: wdl::ConstantWDL { value: 0.75 },
-        lr_scheduler: lr::ExponentialDecayLR {
-            initial_lr: 1e-3,
-            final_lr: 1e-7,
-            final_superbatch: 750,
+        // Only the result matters to a WDL head
+        wdl_scheduler: if wdl_head {
+            Wdl::Constant(wdl::ConstantWDL { value: 1.0 })
+        } else {
+            Wdl::new(config.wdl)
         },
-        save_rate: 10,
+        lr_scheduler: Lr::new(config.lr, config.superbatches),
+        save_rate: config.save_rate,
     };
 
     let settings = LocalSettings {
-        threads: 4,
+        threads: config.threads,
         test_set: None,
-        output_directory: "checkpoints",
+        output_directory: &config.output,
         batch_queue_size: 512,
     };
 
-    let data_loader = loader::DirectSequentialDataLoader::new(&[
-        "/home/jeff/chess-data/shuffled-test80-jan2023-16tb7p-filter-v6-sk20.min-mar2023.bin",
-    ]);
-
-    trainer.run(&schedule, &settings, &data_loader);
-
-    for fen in [
-        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
-        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1",
-        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
-        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R b KQkq - 0 1",
-        "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
-        "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 b kq - 0 1",
-        "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
-        "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R b KQ - 1 8",
-        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
-        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 0 1",
-    ] {
-        let eval = trainer.eval(fen);
-        println!("FEN: {fen}");
-        println!("EVAL: {}", 400.0 * eval);
+    let data_loader = WeightedLoader::new(&config.data, config.holdout);
+    let wdl_preparer = WdlPreparer::new(data_loader.clone(), schedule.eval_scale);
+    let run = |trainer: &mut Trainer<AdamWOptimiser, ThreatInput>, schedule: &TrainingSchedule<Lr, Wdl>| {
+        if wdl_head {
+            trainer.train_custom(&wdl_preparer, &None, schedule, &settings, |_, _, _, _| {});
+        } else {
+            trainer.run(schedule, &settings, &data_loader);
+        }
+    };
+    // Both kinds of head are compared through the centipawn eval the engine would give the outputs
+    let eval = |trainer: &Trainer<AdamWOptimiser, ThreatInput>, fen: &str| {
+        NetOutput::from_head(&trainer.eval_raw_output(fen)).cp()
+    };
+
+    if let Some(mut validation) = Validation::new(config, schedule.eval_scale) {
+        // Train up to every multiple of validation_rate, validating in between
+        let mut start = schedule.steps.start_superbatch;
+        while start <= config.superbatches {
+            let end = ((start / config.validation_rate + 1) * config.validation_rate).min(config.superbatches);
+            schedule.steps.start_superbatch = start;
+            schedule.steps.end_superbatch = end;
+            run(&mut trainer, &schedule);
+
+            let blend = if wdl_head {
+                1.
+            } else {
+                config.wdl.blend(end, config.superbatches)
+            };
+            validation.run(end, blend, |fen| eval(&trainer, fen));
+            start = end + 1;
+        }
+    } else {
+        run(&mut trainer, &schedule);
+    }
+
+    let evals = CHECK_FENS
+        .iter()
+        .map(|&fen| (fen, eval(&trainer, fen)))
+        .collect::<Vec<_>>();
+    let checkpoint = format!(
+        "{}/{}-{}",
+        settings.output_directory, schedule.net_id, schedule.steps.end_superbatch
+    );
+    if let Err(err) = export(&format!("{checkpoint}/raw.bin"), &checkpoint, &schedule.net_id, &evals) {
+        panic!("Export failed: {err}");
+    }
+}
+
+/// bullet's learning rate schedules are separate types, this picks one at runtime
+#[derive(Clone)]
+enum Lr {
+    Constant(lr::ConstantLR),
+    Step(lr::StepLR),
+    Exponential(lr::ExponentialDecayLR),
+    Cosine(lr::CosineDecayLR),
+}
+
+impl Lr {
+    fn new(schedule: LrSchedule, superbatches: usize) -> Self {
+        match schedule {
+            LrSchedule::Constant { lr } => Self::Constant(lr::ConstantLR { value: lr }),
+            LrSchedule::Step { lr, gamma, step } => Self::Step(lr::StepLR { start: lr, gamma, step }),
+            LrSchedule::Exponential { initial, final_lr } => Self::Exponential(lr::ExponentialDecayLR {
+                initial_lr: initial,
+                final_lr,
+                final_superbatch: superbatches,
+            }),
+            LrSchedule::Cosine { initial, final_lr } => Self::Cosine(lr::CosineDecayLR {
+                initial_lr: initial,
+                final_lr,
+                final_superbatch: superbatches,
+            }),
+        }
+    }
+}
+
+impl lr::LrScheduler for Lr {
+    fn lr(&self, batch: usize, superbatch: usize) -> f32 {
+        match self {
+            Self::Constant(s) => s.lr(batch, superbatch),
+            Self::Step(s) => s.lr(batch, superbatch),
+            Self::Exponential(s) => s.lr(batch, superbatch),
+            Self::Cosine(s) => s.lr(batch, superbatch),
+        }
+    }
+
+    fn colourful(&self) -> String {
+        match self {
+            Self::Constant(s) => s.colourful(),
+            Self::Step(s) => s.colourful(),
+            Self::Exponential(s) => s.colourful(),
+            Self::Cosine(s) => s.colourful(),
+        }
     }
 }
 
+/// Same as [`Lr`], for the blend of eval and game result
+#[derive(Clone)]
+enum Wdl {
+    Constant(wdl::ConstantWDL),
+    Linear(wdl::LinearWDL),
+}
+
+impl Wdl {
+    fn new(schedule: WdlSchedule) -> Self {
+        match schedule {
+            WdlSchedule::Constant(value) => Self::Constant(wdl::ConstantWDL { value }),
+            WdlSchedule::Linear { start, end } => Self::Linear(wdl::LinearWDL { start, end }),
+        }
+    }
+}
+
+impl wdl::WdlScheduler for Wdl {
+    fn blend(&self, batch: usize, superbatch: usize, max: usize) -> f32 {
+        match self {
+            Self::Constant(s) => s.blend(batch, superbatch, max),
+            Self::Linear(s) => s.blend(batch, superbatch, max),
+        }
+    }
+
+    fn colourful(&self) -> String {
+        match self {
+            Self::Constant(s) => s.colourful(),
+            Self::Linear(s) => s.colourful(),
+        }
+    }
+}
+
+/// Dense layers are named l1, l2, ... in the order the engine runs them
+fn dense_layers() -> Vec<(String, usize, usize)> {
+    Architecture::embedded()
+        .dense_shapes()
+        .enumerate()
+        .map(|(i, (inputs, outputs))| (format!("l{}", i + 1), inputs, outputs))
+        .collect()
+}
+
+/// Same scales the engine quantizes with, so bullet's `quantised.bin` matches the integer network the engine builds
+/// from `raw.bin` when it loads a network
+fn quant_targets() -> Vec<(String, QuantTarget)> {
+    let mut targets = vec![
+        ("ftw".to_string(), QuantTarget::I16(QA as i16)),
+        ("ftb".to_string(), QuantTarget::I16(QA as i16)),
+    ];
+    for (name, _, _) in dense_layers() {
+        targets.push((format!("{name}w"), QuantTarget::I8(QB as i16)));
+        targets.push((format!("{name}b"), QuantTarget::I32(QO)));
+    }
+    targets
+}
+
 fn build_network() -> (Graph, Node) {
     let mut builder = GraphBuilder::default();
 
     // inputs
     let stm = builder.create_input("stm", Shape::new(INPUT_SIZE, 1));
     let nstm = builder.create_input("nstm", Shape::new(INPUT_SIZE, 1));
-    let targets = builder.create_input("targets", Shape::new(1, 1));
+    // A WDL head is trained on the one-hot game result, a scalar head on the usual blend of eval and result
+    let wdl_head = Architecture::embedded().wdl_head();
+    let targets = builder.create_input("targets", Shape::new(if wdl_head { 3 } else { 1 }, 1));
 
     let ftw = builder.create_weights("ftw", Shape::new(L1_SIZE, INPUT_SIZE));
     let ftb = builder.create_weights("ftb", Shape::new(L1_SIZE, 1));
 
-    let l1w = builder.create_weights("l1w", Shape::new(16, L1_SIZE * 2));
-    let l1b = builder.create_weights("l1b", Shape::new(16, 1));
-
-    let l2w = builder.create_weights("l2w", Shape::new(32, 16));
-    let l2b = builder.create_weights("l2b", Shape::new(32, 1));
-
-    let l3w = builder.create_weights("l3w", Shape::new(1, 32));
-    let l3b = builder.create_weights("l3b", Shape::new(1, 1));
-
     // inference
-    let ft = sparse_affine_dual_with_activation(&mut builder, ftw, stm, nstm, ftb, Activation::SCReLU);
+    let mut out = sparse_affine_dual_with_activation(&mut builder, ftw, stm, nstm, ftb, Activation::SCReLU);
 
-    let l1 = operations::affine(&mut builder, l1w, ft, l1b);
-    let l1 = operations::activate(&mut builder, l1, Activation::SCReLU);
-
-    let l2 = operations::affine(&mut builder, l2w, l1, l2b);
-    let l2 = operations::activate(&mut builder, l2, Activation::SCReLU);
+    let layers = dense_layers();
+    for (i, (name, inputs, outputs)) in layers.iter().enumerate() {
+        let w = builder.create_weights(&format!("{name}w"), Shape::new(*outputs, *inputs));
+        let b = builder.create_weights(&format!("{name}b"), Shape::new(*outputs, 1));
+        out = operations::affine(&mut builder, w, out, b);
+        if i + 1 < layers.len() {
+            out = operations::activate(&mut builder, out, Activation::SCReLU);
+        }
+    }
+    // The last layer has a head for every bucket, only the one for the position's piece count is trained
+    if OUTPUT_BUCKETS > 1 {
+        let buckets = builder.create_input("buckets", Shape::new(OUTPUT_BUCKETS, 1));
+        out = operations::select(&mut builder, out, buckets);
+    }
 
-    let predicted = operations::affine(&mut builder, l3w, l2, l3b);
-    let sigmoided = operations::activate(&mut builder, predicted, Activation::Sigmoid);
-    operations::mse(&mut builder, sigmoided, targets);
+    let predicted = out;
+    if wdl_head {
+        operations::softmax_crossentropy_loss(&mut builder, predicted, targets);
+    } else {
+        let sigmoided = operations::activate(&mut builder, predicted, Activation::Sigmoid);
+        operations::mse(&mut builder, sigmoided, targets);
+    }
 
     // graph, output node
     (builder.build(ExecutionContext::default()), predicted)
@@ -130,16 +285,12 @@ fn seed_weights(graph: &mut Graph) {
     graph
         .get_weights_mut("ftb")
         .seed_random(0.0, 1.0 / (INPUT_SIZE as f32).sqrt(), true);
-    graph
-        .get_weights_mut("l1w")
-        .seed_random(0.0, 1.0 / (2. * L1_SIZE as f32).sqrt(), true);
-    graph
-        .get_weights_mut("l1b")
-        .seed_random(0.0, 1.0 / (2. * L1_SIZE as f32).sqrt(), true);
 
-    for name in ["l2w", "l2b", "l3w", "l3b"] {
-        graph
-            .get_weights_mut(name)
-            .seed_random(0.0, 1.0 / (16_f32).sqrt(), true);
+    for (name, inputs, _) in dense_layers() {
+        for param in ["w", "b"] {
+            graph
+                .get_weights_mut(&format!("{name}{param}"))
+                .seed_random(0.0, 1.0 / (inputs as f32).sqrt(), true);
+        }
     }
 }
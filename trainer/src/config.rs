@@ -0,0 +1,323 @@
+//! Settings for a training run, read from config files and command line arguments.
+//!
+//! Config files hold one `key = value` pair per line, and `#` starts a comment. Any key can also be given on the
+//! command line as `--key value`. Arguments are applied in order, so `--key value` after a config file overrides
+//! the file. Every `data` entry adds another file rather than replacing the previous ones.
+
+use std::fs;
+
+pub const USAGE: &str = "\
+Usage: trainer [config file] [--key value]...
+
+Keys:
+  net_id <name>                    name of the run, used for checkpoint folders
+  data <path> [weight]             data file to train on, can be given multiple times. Files are mixed into every
+                                   batch in proportion to their weights, which default to 1.
+  output <dir>                     folder checkpoints are written to
+  threads <n>                      threads used to load data
+  batch_size <n>
+  batches_per_superbatch <n>
+  superbatches <n>                 superbatch the run ends at
+  save_rate <n>                    superbatches between checkpoints
+  lr constant <lr>
+  lr step <lr> <gamma> <step>      multiply by gamma every step superbatches
+  lr exponential <initial> <final> decay to final at the last superbatch
+  lr cosine <initial> <final>
+  wdl constant <blend>             how much of the target is the game result rather than the eval
+  wdl linear <start> <end>
+  resume <checkpoint dir>          continue from a checkpoint, such as checkpoints/threats-100
+  start_superbatch <n>             superbatch to resume at, read from the checkpoint name when not given
+  validation <path>                data file to measure validation loss on
+  holdout <n>                      keep the last n positions of every data file out of training and validate on
+                                   them instead
+  validation_rate <n>              superbatches between validation runs, logged to {output}/{net_id}-validation.csv
+  validation_positions <n>         most positions to validate on";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataFile {
+    pub path: String,
+    pub weight: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LrSchedule {
+    Constant {
+        lr: f32,
+    },
+    Step {
+        lr: f32,
+        gamma: f32,
+        step: usize,
+    },
+    /// Decays to `final_lr` at the end of the run
+    Exponential {
+        initial: f32,
+        final_lr: f32,
+    },
+    /// Decays to `final_lr` at the end of the run
+    Cosine {
+        initial: f32,
+        final_lr: f32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WdlSchedule {
+    Constant(f32),
+    /// Moves from `start` to `end` over the run
+    Linear {
+        start: f32,
+        end: f32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub net_id: String,
+    pub data: Vec<DataFile>,
+    pub output: String,
+    pub threads: usize,
+    pub batch_size: usize,
+    pub batches_per_superbatch: usize,
+    pub superbatches: usize,
+    pub save_rate: usize,
+    pub lr: LrSchedule,
+    pub wdl: WdlSchedule,
+    pub resume: Option<String>,
+    pub start_superbatch: Option<usize>,
+    pub validation: Option<String>,
+    /// Positions at the end of every data file that are validated on instead of trained on
+    pub holdout: usize,
+    pub validation_rate: usize,
+    pub validation_positions: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            net_id: "threats".to_string(),
+            data: Vec::new(),
+            output: "checkpoints".to_string(),
+            threads: 4,
+            batch_size: 16_384,
+            batches_per_superbatch: 6104,
+            superbatches: 750,
+            save_rate: 10,
+            lr: LrSchedule::Exponential {
+                initial: 1e-3,
+                final_lr: 1e-7,
+            },
+            wdl: WdlSchedule::Constant(0.75),
+            resume: None,
+            start_superbatch: None,
+            validation: None,
+            holdout: 0,
+            validation_rate: 10,
+            validation_positions: 16_384,
+        }
+    }
+}
+
+impl Config {
+    /// Builds a config from the trainer's arguments, see [`USAGE`]
+    ///
+    /// # Errors
+    /// If a config file can't be read, or a setting is unknown, invalid, or missing
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if let Some(key) = arg.strip_prefix("--") {
+                let value = args.next().ok_or_else(|| format!("--{key} needs a value"))?;
+                config.set(key, value)?;
+            } else {
+                let text = fs::read_to_string(arg).map_err(|err| format!("could not read {arg}: {err}"))?;
+                config.parse(&text).map_err(|err| format!("{arg}: {err}"))?;
+            }
+        }
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Applies every `key = value` line of a config file
+    fn parse(&mut self, text: &str) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected key = value", i + 1))?;
+            self.set(key.trim(), value.trim())
+                .map_err(|err| format!("line {}: {err}", i + 1))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let words = value.split_whitespace().collect::<Vec<_>>();
+        match key {
+            "net_id" => self.net_id = value.to_string(),
+            "output" => self.output = value.to_string(),
+            "resume" => self.resume = Some(value.to_string()),
+            "threads" => self.threads = parse(key, value)?,
+            "batch_size" => self.batch_size = parse(key, value)?,
+            "batches_per_superbatch" => self.batches_per_superbatch = parse(key, value)?,
+            "superbatches" => self.superbatches = parse(key, value)?,
+            "save_rate" => self.save_rate = parse(key, value)?,
+            "start_superbatch" => self.start_superbatch = Some(parse(key, value)?),
+            "validation" => self.validation = Some(value.to_string()),
+            "holdout" => self.holdout = parse(key, value)?,
+            "validation_rate" => self.validation_rate = parse(key, value)?,
+            "validation_positions" => self.validation_positions = parse(key, value)?,
+            "data" => {
+                let (path, weight) = match words.as_slice() {
+                    [path] => (path, 1.),
+                    [path, weight] => (path, parse(key, weight)?),
+                    _ => return Err("data takes a path and an optional weight".to_string()),
+                };
+                self.data.push(DataFile {
+                    path: (*path).to_string(),
+                    weight,
+                });
+            }
+            "lr" => {
+                self.lr = match words.as_slice() {
+                    ["constant", lr] => LrSchedule::Constant { lr: parse(key, lr)? },
+                    ["step", lr, gamma, step] => LrSchedule::Step {
+                        lr: parse(key, lr)?,
+                        gamma: parse(key, gamma)?,
+                        step: parse(key, step)?,
+                    },
+                    ["exponential", initial, final_lr] => LrSchedule::Exponential {
+                        initial: parse(key, initial)?,
+                        final_lr: parse(key, final_lr)?,
+                    },
+                    ["cosine", initial, final_lr] => LrSchedule::Cosine {
+                        initial: parse(key, initial)?,
+                        final_lr: parse(key, final_lr)?,
+                    },
+                    _ => return Err(format!("unknown lr schedule '{value}'")),
+                }
+            }
+            "wdl" => {
+                self.wdl = match words.as_slice() {
+                    ["constant", blend] => WdlSchedule::Constant(parse(key, blend)?),
+                    ["linear", start, end] => WdlSchedule::Linear {
+                        start: parse(key, start)?,
+                        end: parse(key, end)?,
+                    },
+                    _ => return Err(format!("unknown wdl schedule '{value}'")),
+                }
+            }
+            _ => return Err(format!("unknown key '{key}'")),
+        }
+        Ok(())
+    }
+
+    /// Catches settings that would only fail once the run has started
+    fn check(&mut self) -> Result<(), String> {
+        if self.data.is_empty() {
+            return Err("no data files given".to_string());
+        }
+        if let Some(file) = self.data.iter().find(|f| f.weight.is_nan() || f.weight <= 0.) {
+            return Err(format!("{} needs a positive weight", file.path));
+        }
+        if self.start_superbatch.is_none() {
+            if let Some(resume) = &self.resume {
+                // Checkpoints are named {net_id}-{superbatch}
+                let saved = resume
+                    .trim_end_matches('/')
+                    .rsplit('-')
+                    .next()
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .ok_or_else(|| format!("can't tell what superbatch {resume} was saved at, set start_superbatch"))?;
+                self.start_superbatch = Some(saved + 1);
+            }
+        }
+        if self.validation_rate == 0 {
+            return Err("validation_rate must be at least 1".to_string());
+        }
+        if self.start_superbatch.unwrap_or(0) > self.superbatches {
+            return Err("the run would start after its last superbatch".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl WdlSchedule {
+    /// How much of the target is the game result at `superbatch`
+    pub fn blend(self, superbatch: usize, superbatches: usize) -> f32 {
+        match self {
+            Self::Constant(blend) => blend,
+            Self::Linear { start, end } => start + (end - start) * superbatch as f32 / superbatches as f32,
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value '{value}' for {key}"))
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::{Config, DataFile, LrSchedule, WdlSchedule};
+
+    #[test]
+    fn file_and_overrides() {
+        let mut config = Config::default();
+        config
+            .parse(
+                "# a comment
+                net_id = test
+                data = a.bin 3
+                data = b.bin
+                lr = step 0.001 0.3 100 # trailing comment
+                wdl = linear 0.2 0.8
+                resume = checkpoints/test-40",
+            )
+            .unwrap();
+        config.set("superbatches", "200").unwrap();
+        config.check().unwrap();
+
+        assert_eq!(config.net_id, "test");
+        assert_eq!(config.superbatches, 200);
+        assert_eq!(
+            config.data,
+            [
+                DataFile {
+                    path: "a.bin".to_string(),
+                    weight: 3.
+                },
+                DataFile {
+                    path: "b.bin".to_string(),
+                    weight: 1.
+                },
+            ]
+        );
+        assert_eq!(
+            config.lr,
+            LrSchedule::Step {
+                lr: 0.001,
+                gamma: 0.3,
+                step: 100
+            }
+        );
+        assert_eq!(config.wdl, WdlSchedule::Linear { start: 0.2, end: 0.8 });
+        assert_eq!(config.start_superbatch, Some(41));
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        assert!(Config::default().check().is_err());
+        assert!(Config::default().parse("threads 4").is_err());
+        assert!(Config::default().parse("thread = 4").is_err());
+        assert!(Config::default().parse("lr = linear 1 2").is_err());
+        assert!(Config::default().parse("data = a.bin 1 2").is_err());
+
+        let mut config = Config::default();
+        config.parse("data = a.bin\nresume = somewhere").unwrap();
+        assert!(config.check().is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{advanced::MaterialBuckets, config::DataFile, threat_inputs::ThreatInput};
+use bullet::{
+    format::ChessBoard,
+    loader::{DataLoader, DataPreparer, DenseInput, PreparedData},
+    Shape,
+};
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    mem::size_of,
+};
+
+/// Loads batches mixed from several bulletformat files. Every batch takes the same number of positions from each
+/// file, in proportion to its weight, and files start over from the beginning once they run out.
+#[derive(Clone)]
+pub struct WeightedLoader {
+    paths: Vec<String>,
+    weights: Vec<f64>,
+    /// Positions at the end of each file that are never trained on
+    holdout: u64,
+}
+
+impl WeightedLoader {
+    pub fn new(files: &[DataFile], holdout: usize) -> Self {
+        Self {
+            paths: files.iter().map(|f| f.path.clone()).collect(),
+            weights: files.iter().map(|f| f.weight).collect(),
+            holdout: holdout as u64,
+        }
+    }
+}
+
+/// Number of positions in a bulletformat file
+pub fn position_count(path: &str) -> u64 {
+    let bytes = std::fs::metadata(path)
+        .unwrap_or_else(|err| panic!("Could not open {path}: {err}"))
+        .len();
+    bytes / size_of::<ChessBoard>() as u64
+}
+
+/// Reads up to `count` positions starting at position `start`
+pub fn read_positions(path: &str, start: u64, count: usize) -> Vec<ChessBoard> {
+    let available = position_count(path).saturating_sub(start);
+    let mut reader = PositionReader::open(path, start, u64::MAX);
+    (0..available.min(count as u64))
+        .map(|_| reader.next_position())
+        .collect()
+}
+
+impl DataLoader<ChessBoard> for WeightedLoader {
+    fn data_file_paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    fn count_positions(&self) -> Option<u64> {
+        Some(
+            self.paths
+                .iter()
+                .map(|path| position_count(path).saturating_sub(self.holdout))
+                .sum(),
+        )
+    }
+
+    fn map_batches<F: FnMut(&[ChessBoard]) -> bool>(&self, start_batch: usize, batch_size: usize, mut f: F) {
+        let quotas = quotas(&self.weights, batch_size);
+        // Since every batch has the same makeup, resuming only needs to skip that many batches worth of each file
+        let mut readers = self
+            .paths
+            .iter()
+            .zip(&quotas)
+            .map(|(path, &quota)| {
+                let positions = position_count(path).saturating_sub(self.holdout);
+                assert!(positions > 0, "{path} has no positions left to train on");
+                PositionReader::open(path, (start_batch * quota) as u64 % positions, positions)
+            })
+            .collect::<Vec<_>>();
+
+        let mut batch = Vec::with_capacity(batch_size);
+        loop {
+            batch.clear();
+            for (reader, &quota) in readers.iter_mut().zip(&quotas) {
+                for _ in 0..quota {
+                    batch.push(reader.next_position());
+                }
+            }
+            if f(&batch) {
+                break;
+            }
+        }
+    }
+}
+
+/// How many positions of each batch come from each file. Rounds so that the quotas add up to `batch_size`.
+pub fn quotas(weights: &[f64], batch_size: usize) -> Vec<usize> {
+    let total = weights.iter().sum::<f64>();
+    let exact = weights
+        .iter()
+        .map(|w| w / total * batch_size as f64)
+        .collect::<Vec<_>>();
+    let mut quotas = exact.iter().map(|x| x.floor() as usize).collect::<Vec<_>>();
+
+    // Hand out what's left to the files that lost the most to rounding
+    let mut by_remainder = (0..weights.len()).collect::<Vec<_>>();
+    by_remainder.sort_by(|&a, &b| (exact[b] - exact[b].floor()).total_cmp(&(exact[a] - exact[a].floor())));
+    let missing = batch_size - quotas.iter().sum::<usize>();
+    for &i in by_remainder.iter().cycle().take(missing) {
+        quotas[i] += 1;
+    }
+    quotas
+}
+
+/// Target of a WDL head for a game result from the side to move's point of view, one-hot [win, draw, loss]
+pub fn wdl_target(result: f32) -> [f32; 3] {
+    if result > 0.75 {
+        [1., 0., 0.]
+    } else if result < 0.25 {
+        [0., 0., 1.]
+    } else {
+        [0., 1., 0.]
+    }
+}
+
+/// The targets of a batch for a WDL head, laid out one position after another
+pub fn wdl_targets(batch: &[ChessBoard]) -> DenseInput {
+    DenseInput {
+        shape: Shape::new(3, batch.len()),
+        value: batch.iter().flat_map(|pos| wdl_target(pos.result())).collect(),
+    }
+}
+
+/// Prepares batches for networks with a WDL head. Inputs and buckets are prepared by bullet as usual, but the
+/// single blended target is swapped for the one-hot game result, since a WDL head is trained on that alone.
+#[derive(Clone)]
+pub struct WdlPreparer {
+    loader: WeightedLoader,
+    eval_scale: f32,
+}
+
+impl WdlPreparer {
+    pub const fn new(loader: WeightedLoader, eval_scale: f32) -> Self {
+        Self { loader, eval_scale }
+    }
+}
+
+impl DataPreparer for WdlPreparer {
+    type DataType = ChessBoard;
+    type PreparedData = PreparedData;
+
+    fn get_data_file_paths(&self) -> &[String] {
+        self.loader.data_file_paths()
+    }
+
+    fn try_count_positions(&self) -> Option<u64> {
+        self.loader.count_positions()
+    }
+
+    fn load_and_map_batches<F: FnMut(&[ChessBoard]) -> bool>(&self, start_batch: usize, batch_size: usize, f: F) {
+        self.loader.map_batches(start_batch, batch_size, f);
+    }
+
+    fn prepare(&self, data: &[ChessBoard], threads: usize, blend: f32) -> PreparedData {
+        let mut prepared = PreparedData::new(
+            ThreatInput,
+            MaterialBuckets,
+            false,
+            data,
+            threads,
+            blend,
+            self.eval_scale,
+        );
+        prepared.targets = wdl_targets(data);
+        prepared
+    }
+}
+
+/// Reads positions from a bulletformat file forever, wrapping around after the first `limit` positions
+struct PositionReader {
+    path: String,
+    reader: BufReader<File>,
+    /// Position the reader is at
+    pos: u64,
+    limit: u64,
+}
+
+impl PositionReader {
+    fn open(path: &str, start: u64, limit: u64) -> Self {
+        let file = File::open(path).unwrap_or_else(|err| panic!("Could not open {path}: {err}"));
+        let mut reader = BufReader::with_capacity(1 << 20, file);
+        reader
+            .seek(SeekFrom::Start(start * size_of::<ChessBoard>() as u64))
+            .unwrap();
+        Self {
+            path: path.to_string(),
+            reader,
+            pos: start,
+            limit,
+        }
+    }
+
+    fn next_position(&mut self) -> ChessBoard {
+        let mut bytes = [0; size_of::<ChessBoard>()];
+        if self.pos >= self.limit || self.reader.read_exact(&mut bytes).is_err() {
+            self.reader.rewind().unwrap();
+            self.pos = 0;
+            self.reader
+                .read_exact(&mut bytes)
+                .unwrap_or_else(|err| panic!("Could not read {}: {err}", self.path));
+        }
+        self.pos += 1;
+        // Bulletformat files are the in memory representation of `ChessBoard`, written back to back
+        unsafe { std::ptr::read_unaligned(bytes.as_ptr().cast()) }
+    }
+}
+
+#[cfg(test)]
+mod data_tests {
+    use super::{quotas, wdl_target, wdl_targets};
+    use bullet::format::ChessBoard;
+    use imm_cee_tee_ess::{board::Board, to_bulletformat, types::pieces::Color, RECORD_SIZE};
+    use std::{mem::size_of, str::FromStr};
+
+    #[test]
+    fn quotas_fill_batches() {
+        assert_eq!(quotas(&[1.], 16_384), [16_384]);
+        assert_eq!(quotas(&[3., 1.], 16_384), [12_288, 4096]);
+        assert_eq!(quotas(&[1., 1., 1.], 100), [34, 33, 33]);
+        assert_eq!(quotas(&[0.5, 0.25, 0.25], 10).iter().sum::<usize>(), 10);
+    }
+
+    /// Positions written by the engine's datagen have to read back the same as bullet's own parsing of them
+    #[test]
+    fn reads_engine_datagen() {
+        assert_eq!(size_of::<ChessBoard>(), RECORD_SIZE);
+        for (fen, score, result) in [
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 25, 0.5),
+            (
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R b KQkq - 0 1",
+                -140,
+                0.,
+            ),
+            ("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 310, 1.),
+            ("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R b KQ - 1 8", 52, 1.),
+        ] {
+            let board = Board::from_fen(fen);
+            let (white_score, white_result) = if board.stm() == Color::White {
+                (score, result)
+            } else {
+                (-score, 1. - result)
+            };
+            let expected = ChessBoard::from_str(&format!("{fen} | {white_score} | {white_result}")).unwrap();
+
+            let record = to_bulletformat(&board, score, result);
+            let pos: ChessBoard = unsafe { std::ptr::read_unaligned(record.as_ptr().cast()) };
+            assert_eq!(pos.occ(), expected.occ(), "{fen}");
+            assert_eq!(pos.score(), expected.score(), "{fen}");
+            assert!((pos.result() - expected.result()).abs() < 1e-6, "{fen}");
+            assert_eq!(
+                pos.into_iter().collect::<Vec<_>>(),
+                expected.into_iter().collect::<Vec<_>>(),
+                "{fen}"
+            );
+        }
+    }
+
+    #[test]
+    fn wdl_targets_are_one_hot_results() {
+        assert_eq!(wdl_target(1.), [1., 0., 0.]);
+        assert_eq!(wdl_target(0.5), [0., 1., 0.]);
+        assert_eq!(wdl_target(0.), [0., 0., 1.]);
+
+        // Results are stm relative, so a black win written by datagen is a win for black to move
+        let batch = [
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0.5),
+            ("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 1.),
+            ("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R b KQ - 1 8", 1.),
+            (
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R b KQkq - 0 1",
+                0.,
+            ),
+        ]
+        .map(|(fen, result)| {
+            let record = to_bulletformat(&Board::from_fen(fen), 0, result);
+            unsafe { std::ptr::read_unaligned::<ChessBoard>(record.as_ptr().cast()) }
+        });
+        let targets = wdl_targets(&batch);
+        assert_eq!((targets.shape.rows(), targets.shape.cols()), (3, 4));
+        assert_eq!(targets.value, [0., 1., 0., 1., 0., 0., 1., 0., 0., 0., 0., 1.]);
+    }
+}
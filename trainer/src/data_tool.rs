@@ -0,0 +1,413 @@
+//! Converts training data between formats and reports statistics on it, so broken files are caught before a run
+//! rather than days into one.
+
+use crate::threat_inputs::to_board;
+use bullet::format::ChessBoard;
+use imm_cee_tee_ess::{parse_visits, to_bulletformat};
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    fmt,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    mem::size_of,
+    str::FromStr,
+};
+
+pub const DATA_USAGE: &str = "\
+Usage: trainer convert <input> <output> [--from format] [--to format]
+       trainer stats <input> [--from format]
+
+Formats:
+  bullet    bulletformat records, which is also what datagen writes
+  text      `fen | score | wdl` lines, with the score and result from white's point of view
+  visits    `fen | move:visits ...` lines written by `datagen --policy`. These have no scores or results, so they
+            can be inspected but not converted.
+
+The input format is guessed from the contents of the file, and the output format from its extension: `.txt` and
+`.epd` are text, anything else is bullet. Bulletformat doesn't store whose turn it is, so positions converted to text
+from it are written from the side to move's point of view, with white to move.";
+
+/// Scores are bucketed in steps of this many centipawns
+const SCORE_BUCKET: i32 = 200;
+/// Buckets on either side of zero, with the outermost ones also holding everything beyond them
+const SCORE_BUCKETS: i32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Bullet,
+    Text,
+    Visits,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bullet" => Ok(Self::Bullet),
+            "text" => Ok(Self::Text),
+            "visits" => Ok(Self::Visits),
+            _ => Err(format!("unknown format '{s}'")),
+        }
+    }
+}
+
+impl Format {
+    /// Text files are told apart by their first line. Bulletformat records are all but certain to not be valid
+    /// UTF-8 with a `|` in them.
+    fn guess_input(path: &str) -> Result<Self, String> {
+        let mut buf = Vec::new();
+        File::open(path)
+            .and_then(|file| file.take(4096).read_to_end(&mut buf))
+            .map_err(|err| format!("could not read {path}: {err}"))?;
+        let text = match std::str::from_utf8(&buf) {
+            Ok(text) => text,
+            // The first 4096 bytes can end in the middle of a character
+            Err(err) if err.error_len().is_none() => std::str::from_utf8(&buf[..err.valid_up_to()]).unwrap(),
+            Err(_) => return Ok(Self::Bullet),
+        };
+        let Some((_, rest)) = text.lines().next().and_then(|line| line.split_once('|')) else {
+            return Ok(Self::Bullet);
+        };
+        if rest.split_whitespace().next().is_some_and(|field| field.contains(':')) {
+            Ok(Self::Visits)
+        } else {
+            Ok(Self::Text)
+        }
+    }
+
+    fn guess_output(path: &str) -> Self {
+        if path.ends_with(".txt") || path.ends_with(".epd") {
+            Self::Text
+        } else {
+            Self::Bullet
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Settings {
+    input: String,
+    output: Option<String>,
+    from: Option<Format>,
+    to: Option<Format>,
+}
+
+/// Runs `trainer convert` or `trainer stats`, see [`DATA_USAGE`]
+///
+/// # Errors
+/// If the arguments are invalid or the files can't be read or written
+pub fn run(command: &str, args: &[String]) -> Result<(), String> {
+    let settings = parse_args(command, args)?;
+    let from = match settings.from {
+        Some(format) => format,
+        None => Format::guess_input(&settings.input)?,
+    };
+
+    let mut stats = Stats::new(from != Format::Visits);
+    let skipped = if let Some(output) = &settings.output {
+        let to = settings.to.unwrap_or_else(|| Format::guess_output(output));
+        if from == Format::Visits || to == Format::Visits {
+            return Err("visits files have no scores or results to convert".to_string());
+        }
+        let file = File::create(output).map_err(|err| format!("could not create {output}: {err}"))?;
+        let mut writer = BufWriter::new(file);
+        let mut result = Ok(());
+        let skipped = read_positions(&settings.input, from, |pos| {
+            stats.add(pos);
+            if result.is_ok() {
+                result = write_position(&mut writer, pos, to);
+            }
+        })?;
+        result
+            .and_then(|()| writer.flush())
+            .map_err(|err| format!("could not write {output}: {err}"))?;
+        println!("Wrote {} positions to {output}", stats.positions);
+        skipped
+    } else {
+        read_positions(&settings.input, from, |pos| stats.add(pos))?
+    };
+
+    if skipped > 0 {
+        println!("Skipped {skipped} unreadable lines");
+    }
+    print!("{stats}");
+    Ok(())
+}
+
+fn parse_args(command: &str, args: &[String]) -> Result<Settings, String> {
+    let (input, output, flags) = match (command, args) {
+        ("convert", [input, output, flags @ ..]) => (input, Some(output.clone()), flags),
+        ("stats", [input, flags @ ..]) => (input, None, flags),
+        ("convert", _) => return Err("expected an input and an output file".to_string()),
+        _ => return Err("expected an input file".to_string()),
+    };
+    let mut settings = Settings {
+        input: input.clone(),
+        output,
+        from: None,
+        to: None,
+    };
+    for pair in flags.chunks(2) {
+        let [key, value] = pair else {
+            return Err(format!("{} needs a value", pair[0]));
+        };
+        match key.trim_start_matches("--") {
+            "from" => settings.from = Some(value.parse()?),
+            "to" if settings.output.is_some() => settings.to = Some(value.parse()?),
+            _ => return Err(format!("unknown key '{key}'")),
+        }
+    }
+    Ok(settings)
+}
+
+/// Calls `f` on every position of a file, and returns how many lines of a text file couldn't be read
+fn read_positions(path: &str, format: Format, mut f: impl FnMut(&ChessBoard)) -> Result<u64, String> {
+    let file = File::open(path).map_err(|err| format!("could not read {path}: {err}"))?;
+    let mut reader = BufReader::with_capacity(1 << 20, file);
+    let mut skipped = 0;
+    if format == Format::Bullet {
+        let mut bytes = [0; size_of::<ChessBoard>()];
+        loop {
+            match reader.read_exact(&mut bytes) {
+                // Bulletformat files are the in memory representation of `ChessBoard`, written back to back
+                Ok(()) => f(&unsafe { std::ptr::read_unaligned(bytes.as_ptr().cast()) }),
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(skipped),
+                Err(err) => return Err(format!("could not read {path}: {err}")),
+            }
+        }
+    }
+
+    for line in reader.lines() {
+        let line = line.map_err(|err| format!("could not read {path}: {err}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let pos = if format == Format::Text {
+            ChessBoard::from_str(&line).ok()
+        } else {
+            parse_visits(&line).map(|(board, _)| {
+                let record = to_bulletformat(&board, 0, 0.5);
+                unsafe { std::ptr::read_unaligned(record.as_ptr().cast()) }
+            })
+        };
+        match pos {
+            Some(pos) => f(&pos),
+            None => skipped += 1,
+        }
+    }
+    Ok(skipped)
+}
+
+fn write_position(writer: &mut impl Write, pos: &ChessBoard, format: Format) -> std::io::Result<()> {
+    match format {
+        Format::Bullet => {
+            let bytes =
+                unsafe { std::slice::from_raw_parts(std::ptr::from_ref(pos).cast::<u8>(), size_of::<ChessBoard>()) };
+            writer.write_all(bytes)
+        }
+        Format::Text => {
+            // Castling rights and en passant squares aren't stored either, so don't make any up
+            let fen = to_board(pos).to_fen();
+            let fields = fen.split_whitespace().collect::<Vec<_>>();
+            let fen = format!("{} {} - - {}", fields[0], fields[1], fields[4..].join(" "));
+            writeln!(writer, "{fen} | {} | {:.1}", pos.score(), pos.result())
+        }
+        Format::Visits => unreachable!(),
+    }
+}
+
+struct Stats {
+    /// Visits files only have positions, so their scores and results mean nothing
+    has_scores: bool,
+    positions: u64,
+    /// Losses, draws and wins for the side to move
+    results: [u64; 3],
+    /// Results that aren't a loss, draw or win
+    odd_results: u64,
+    /// Positions by number of pieces on the board, kings included
+    pieces: [u64; 33],
+    scores: [u64; 2 * SCORE_BUCKETS as usize],
+    /// Positions without exactly one king per side
+    bad_kings: u64,
+    seen: HashSet<u64>,
+    duplicates: u64,
+}
+
+impl Stats {
+    fn new(has_scores: bool) -> Self {
+        Self {
+            has_scores,
+            positions: 0,
+            results: [0; 3],
+            odd_results: 0,
+            pieces: [0; 33],
+            scores: [0; 2 * SCORE_BUCKETS as usize],
+            bad_kings: 0,
+            seen: HashSet::new(),
+            duplicates: 0,
+        }
+    }
+
+    fn add(&mut self, pos: &ChessBoard) {
+        self.positions += 1;
+
+        let result = pos.result();
+        if result == 0. || result == 0.5 || result == 1. {
+            self.results[(result * 2.) as usize] += 1;
+        } else {
+            self.odd_results += 1;
+        }
+
+        let bucket = (i32::from(pos.score()).div_euclid(SCORE_BUCKET) + SCORE_BUCKETS).clamp(0, 2 * SCORE_BUCKETS - 1);
+        self.scores[bucket as usize] += 1;
+
+        let pieces = pos.into_iter().collect::<Vec<_>>();
+        self.pieces[pieces.len().min(32)] += 1;
+        let kings = |color: u8| pieces.iter().filter(|&&(piece, _)| piece == color | 5).count();
+        if kings(0) != 1 || kings(8) != 1 {
+            self.bad_kings += 1;
+        }
+
+        // Duplicates are the same pieces on the same squares, whatever their scores and results
+        let mut hasher = DefaultHasher::new();
+        pos.occ().hash(&mut hasher);
+        pieces.hash(&mut hasher);
+        if !self.seen.insert(hasher.finish()) {
+            self.duplicates += 1;
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |n: u64| 100. * n as f64 / self.positions.max(1) as f64;
+        writeln!(f, "positions: {}", self.positions)?;
+        writeln!(f, "duplicates: {} ({:.2}%)", self.duplicates, percent(self.duplicates))?;
+        if self.bad_kings > 0 {
+            writeln!(
+                f,
+                "WARNING: {} positions without exactly one king per side",
+                self.bad_kings
+            )?;
+        }
+
+        if self.has_scores {
+            let [losses, draws, wins] = self.results;
+            writeln!(
+                f,
+                "results for the side to move: {:.1}% wins, {:.1}% draws, {:.1}% losses",
+                percent(wins),
+                percent(draws),
+                percent(losses)
+            )?;
+            if self.odd_results > 0 {
+                writeln!(
+                    f,
+                    "WARNING: {} results that aren't a win, draw or loss",
+                    self.odd_results
+                )?;
+            }
+
+            writeln!(f, "\nscores:")?;
+            for (i, &count) in self.scores.iter().enumerate() {
+                let low = (i as i32 - SCORE_BUCKETS) * SCORE_BUCKET;
+                let label = match i as i32 {
+                    0 => format!("< {}", low + SCORE_BUCKET),
+                    i if i == 2 * SCORE_BUCKETS - 1 => format!(">= {low}"),
+                    _ => format!("{low}..{}", low + SCORE_BUCKET),
+                };
+                writeln!(f, "{label:>12} {:>6.2}% {}", percent(count), bar(percent(count)))?;
+            }
+        }
+
+        writeln!(f, "\npieces:")?;
+        for (pieces, &count) in self.pieces.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            writeln!(f, "{pieces:>12} {:>6.2}% {}", percent(count), bar(percent(count)))?;
+        }
+        Ok(())
+    }
+}
+
+fn bar(percent: f64) -> String {
+    "#".repeat((percent / 2.).round() as usize)
+}
+
+#[cfg(test)]
+mod data_tool_tests {
+    use super::{read_positions, write_position, Format, Stats};
+    use bullet::format::ChessBoard;
+    use std::{fs, io::Write, str::FromStr};
+
+    const LINES: [&str; 4] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 | 25 | 0.5",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1 | -140 | 0.0",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1 | 310 | 1.0",
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 | 25 | 0.5",
+    ];
+
+    fn read_all(path: &str, format: Format) -> Vec<ChessBoard> {
+        let mut positions = Vec::new();
+        assert_eq!(read_positions(path, format, |pos| positions.push(*pos)), Ok(0));
+        positions
+    }
+
+    fn assert_same(a: &[ChessBoard], b: &[ChessBoard]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert_eq!(a.occ(), b.occ());
+            assert_eq!(a.score(), b.score());
+            assert_eq!(a.result(), b.result());
+            assert_eq!(a.into_iter().collect::<Vec<_>>(), b.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn text_bullet_round_trip() {
+        let dir = std::env::temp_dir();
+        let text = dir.join("data_tool_in.txt").to_str().unwrap().to_string();
+        let bullet = dir.join("data_tool.bin").to_str().unwrap().to_string();
+        let back = dir.join("data_tool_out.txt").to_str().unwrap().to_string();
+        fs::write(&text, LINES.join("\n")).unwrap();
+
+        assert_eq!(Format::guess_input(&text), Ok(Format::Text));
+        let expected = LINES
+            .iter()
+            .map(|line| ChessBoard::from_str(line).unwrap())
+            .collect::<Vec<_>>();
+        let positions = read_all(&text, Format::Text);
+        assert_same(&positions, &expected);
+
+        for (path, format) in [(&bullet, Format::Bullet), (&back, Format::Text)] {
+            let mut file = fs::File::create(path).unwrap();
+            for pos in &positions {
+                write_position(&mut file, pos, format).unwrap();
+            }
+            file.flush().unwrap();
+            assert_eq!(Format::guess_input(path), Ok(format));
+            assert_same(&read_all(path, format), &expected);
+        }
+
+        let mut stats = Stats::new(true);
+        positions.iter().for_each(|pos| stats.add(pos));
+        assert_eq!(stats.positions, 4);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.results, [1, 2, 1]);
+        assert_eq!(stats.pieces[32], 3);
+        assert_eq!(stats.bad_kings, 0);
+    }
+
+    #[test]
+    fn recognizes_visits() {
+        let path = std::env::temp_dir().join("data_tool.visits");
+        let path = path.to_str().unwrap();
+        fs::write(
+            path,
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 | e2e4:12 d2d4:8\n",
+        )
+        .unwrap();
+        assert_eq!(Format::guess_input(path), Ok(Format::Visits));
+        assert_eq!(read_all(path, Format::Visits).len(), 1);
+    }
+}
@@ -0,0 +1,80 @@
+use imm_cee_tee_ess::{
+    board::Board,
+    eval::{loader, network::Architecture},
+};
+use std::fs;
+
+/// Positions the engine has to agree with the trainer on after a network is exported
+pub const CHECK_FENS: [&str; 10] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R b KQkq - 0 1",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 b kq - 0 1",
+    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R b KQ - 1 8",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 0 1",
+];
+
+/// The engine runs the same float network as the trainer, so anything beyond rounding means the two disagree on
+/// how the weights are laid out
+fn float_tolerance(eval: f32) -> f32 {
+    1. + 0.005 * eval.abs()
+}
+
+/// Same bound the engine's own tests hold the quantized network to
+fn quantized_tolerance(eval: f32) -> f32 {
+    50. + 0.1 * eval.abs()
+}
+
+/// Turns the raw weights of a bullet checkpoint into the files the engine uses, then loads them back through the
+/// engine and checks it evaluates `trainer_evals` the way the trainer did.
+///
+/// Writes `{name}-raw.bin`, which can be copied over `bins/raw.bin` to embed the network, and `{name}.bin`, which
+/// can be loaded through `EvalFile`.
+///
+/// # Errors
+/// If the files can't be written or read back, or any evaluation is out of tolerance. Every mismatch is listed.
+pub fn export(checkpoint_raw: &str, output_dir: &str, name: &str, trainer_evals: &[(&str, f32)]) -> Result<(), String> {
+    let arch = Architecture::embedded();
+    let raw = fs::read(checkpoint_raw).map_err(|err| format!("could not read {checkpoint_raw}: {err}"))?;
+    let weights = loader::trim_raw_weights(&arch, raw).map_err(|err| format!("{checkpoint_raw}: {err}"))?;
+
+    let raw_path = format!("{output_dir}/{name}-raw.bin");
+    fs::write(&raw_path, &weights).map_err(|err| format!("could not write {raw_path}: {err}"))?;
+    let net_path = format!("{output_dir}/{name}.bin");
+    loader::write_network(arch.clone(), weights, &net_path).map_err(|err| format!("{net_path}: {err}"))?;
+    loader::load_network(&net_path).map_err(|err| format!("engine could not load {net_path}: {err}"))?;
+    println!("Exported {arch} network to {raw_path} and {net_path}");
+
+    let mut mismatches = Vec::new();
+    for &(fen, trainer) in trainer_evals {
+        let board = Board::from_fen(fen);
+        let float = board.float_eval();
+        let quantized = board.raw_eval();
+        println!("{fen}: trainer {trainer:.2}, engine float {float:.2}, engine quantized {quantized:.2}");
+
+        if (trainer - float).abs() > float_tolerance(trainer) {
+            mismatches.push(format!(
+                "{fen}: trainer says {trainer:.2} but the engine's float network says {float:.2}"
+            ));
+        }
+        if (float - quantized).abs() > quantized_tolerance(float) {
+            mismatches.push(format!(
+                "{fen}: float network says {float:.2} but the quantized one says {quantized:.2}"
+            ));
+        }
+    }
+    loader::use_embedded_network();
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "exported network doesn't match the trainer:\n{}",
+            mismatches.join("\n")
+        ))
+    }
+}
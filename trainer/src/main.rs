@@ -1,6 +1,39 @@
 mod advanced;
+mod config;
+mod data;
+mod data_tool;
+mod export;
+mod policy;
 mod threat_inputs;
+mod validation;
+
+use config::{Config, USAGE};
 
 fn main() {
-    advanced::train();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().is_some_and(|arg| arg == "policy") {
+        if let Err(err) = policy::train_policy(&args[1..]) {
+            eprintln!("{err}\n\n{}", policy::POLICY_USAGE);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(command @ ("convert" | "stats")) = args.first().map(String::as_str) {
+        if let Err(err) = data_tool::run(command, &args[1..]) {
+            eprintln!("{err}\n\n{}", data_tool::DATA_USAGE);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return;
+    }
+    match Config::from_args(&args) {
+        Ok(config) => advanced::train(&config),
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,336 @@
+//! Trains the policy network on the root visit distributions datagen writes with `--policy`.
+//!
+//! The network is small enough that bullet isn't needed, so this is plain Adam on the CPU.
+
+use imm_cee_tee_ess::{
+    board::Board,
+    chess_move::Move,
+    parse_visits,
+    policy::network::{
+        activate, activation_gradient, input_features, move_slots, read_policy, write_policy, PolicyNetwork,
+        DEFAULT_HIDDEN,
+    },
+};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    time::Instant,
+};
+
+pub const POLICY_USAGE: &str = "\
+Usage: trainer policy <visits file> <output file> [--key value]...
+
+Keys:
+  hidden <n>        hidden layer size of a new network
+  epochs <n>
+  batch_size <n>
+  lr <lr>           decays linearly to a tenth of this over the run
+  resume <file>     continue training a policy file instead of starting from random weights
+  seed <n>          seed for the initial weights and the order positions are seen in";
+
+/// Every `VALIDATION_EVERY`th position is kept out of training to measure the loss on
+const VALIDATION_EVERY: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Settings {
+    data: String,
+    output: String,
+    hidden: usize,
+    epochs: usize,
+    batch_size: usize,
+    lr: f32,
+    resume: Option<String>,
+    seed: u64,
+}
+
+/// A position along with the share of visits each of its moves got
+struct Sample {
+    features: Vec<usize>,
+    slots: Vec<[usize; 2]>,
+    targets: Vec<f32>,
+}
+
+impl Sample {
+    fn new(board: &Board, visits: &[(Move, i32)]) -> Option<Self> {
+        let total = visits.iter().map(|&(_, n)| n).sum::<i32>();
+        if visits.len() < 2 || total <= 0 {
+            return None;
+        }
+        Some(Self {
+            features: input_features(board).to_vec(),
+            slots: visits.iter().map(|&(m, _)| move_slots(board, m)).collect(),
+            targets: visits.iter().map(|&(_, n)| n as f32 / total as f32).collect(),
+        })
+    }
+}
+
+/// Trains a policy network, see [`POLICY_USAGE`]
+///
+/// # Errors
+/// If the arguments are invalid or the data can't be read
+pub fn train_policy(args: &[String]) -> Result<(), String> {
+    let settings = parse_args(args)?;
+    let file = File::open(&settings.data).map_err(|err| format!("could not read {}: {err}", settings.data))?;
+    let mut samples = Vec::new();
+    let mut skipped = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|err| format!("could not read {}: {err}", settings.data))?;
+        match parse_visits(&line).and_then(|(board, visits)| Sample::new(&board, &visits)) {
+            Some(sample) => samples.push(sample),
+            None => skipped += 1,
+        }
+    }
+    let validation = (0..samples.len())
+        .rev()
+        .filter(|i| i % VALIDATION_EVERY == 0)
+        .map(|i| samples.swap_remove(i))
+        .collect::<Vec<_>>();
+    if samples.is_empty() {
+        return Err(format!("{} has no usable positions", settings.data));
+    }
+    println!(
+        "Training on {} positions, validating on {}, skipped {skipped} lines",
+        samples.len(),
+        validation.len()
+    );
+
+    let mut rng = settings.seed.max(1);
+    let mut net = match &settings.resume {
+        Some(path) => read_policy(path).map_err(|err| format!("could not load {path}: {err}"))?,
+        None => random_network(settings.hidden, &mut rng),
+    };
+    let mut adam = Adam::new(PolicyNetwork::parameter_count(net.hidden));
+    let mut order = (0..samples.len()).collect::<Vec<_>>();
+    let batches = samples.len().div_ceil(settings.batch_size) * settings.epochs;
+    let start = Instant::now();
+
+    for epoch in 1..=settings.epochs {
+        shuffle(&mut order, &mut rng);
+        let mut total_loss = 0.;
+        for batch in order.chunks(settings.batch_size) {
+            let mut grads = PolicyNetwork::zeroed(net.hidden);
+            for &i in batch {
+                total_loss += backward(&net, &samples[i], &mut grads);
+            }
+            let progress = adam.step as f32 / batches as f32;
+            let lr = settings.lr * (1. - 0.9 * progress);
+            let grads = grads
+                .to_floats()
+                .iter()
+                .map(|g| g / batch.len() as f32)
+                .collect::<Vec<_>>();
+            let mut params = net.to_floats();
+            adam.update(&mut params, &grads, lr);
+            net = PolicyNetwork::from_floats(net.hidden, &params);
+        }
+
+        let validation_loss = validation.iter().map(|s| loss(&net, s)).sum::<f32>() / validation.len().max(1) as f32;
+        println!(
+            "epoch {epoch}: loss {:.5}, validation loss {validation_loss:.5}, {:.0}s",
+            total_loss / samples.len() as f32,
+            start.elapsed().as_secs_f32()
+        );
+        write_policy(&net, &settings.output).map_err(|err| format!("could not write {}: {err}", settings.output))?;
+    }
+    println!("Wrote {}", settings.output);
+    Ok(())
+}
+
+fn parse_args(args: &[String]) -> Result<Settings, String> {
+    let [data, output, flags @ ..] = args else {
+        return Err("expected a visits file and an output file".to_string());
+    };
+    let mut settings = Settings {
+        data: data.clone(),
+        output: output.clone(),
+        hidden: DEFAULT_HIDDEN,
+        epochs: 10,
+        batch_size: 1024,
+        lr: 1e-3,
+        resume: None,
+        seed: 1,
+    };
+    for pair in flags.chunks(2) {
+        let [key, value] = pair else {
+            return Err(format!("{} needs a value", pair[0]));
+        };
+        let invalid = || format!("invalid value '{value}' for {key}");
+        match key.trim_start_matches("--") {
+            "hidden" => settings.hidden = value.parse().map_err(|_| invalid())?,
+            "epochs" => settings.epochs = value.parse().map_err(|_| invalid())?,
+            "batch_size" => settings.batch_size = value.parse().map_err(|_| invalid())?,
+            "lr" => settings.lr = value.parse().map_err(|_| invalid())?,
+            "seed" => settings.seed = value.parse().map_err(|_| invalid())?,
+            "resume" => settings.resume = Some(value.clone()),
+            _ => return Err(format!("unknown key '{key}'")),
+        }
+    }
+    if settings.hidden == 0 || settings.batch_size == 0 {
+        return Err("hidden and batch_size must be at least 1".to_string());
+    }
+    Ok(settings)
+}
+
+/// Softmax over the logits of a sample's moves, along with the activated hidden layer and its sums
+fn forward(net: &PolicyNetwork, sample: &Sample) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+    let sums = net.hidden_sums(&sample.features);
+    let hidden = sums.iter().map(|&x| activate(x)).collect::<Vec<_>>();
+    let logits = sample
+        .slots
+        .iter()
+        .map(|&slots| net.logit(&hidden, slots))
+        .collect::<Vec<_>>();
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+    let denom = exps.iter().sum::<f32>();
+    (exps.iter().map(|e| e / denom).collect(), hidden, sums)
+}
+
+/// Cross entropy between the visit distribution and the policy
+fn cross_entropy(targets: &[f32], probs: &[f32]) -> f32 {
+    -targets
+        .iter()
+        .zip(probs)
+        .map(|(t, p)| t * p.max(1e-12).ln())
+        .sum::<f32>()
+}
+
+fn loss(net: &PolicyNetwork, sample: &Sample) -> f32 {
+    cross_entropy(&sample.targets, &forward(net, sample).0)
+}
+
+/// Adds the gradient of the loss of one sample to `grads` and returns the loss
+fn backward(net: &PolicyNetwork, sample: &Sample, grads: &mut PolicyNetwork) -> f32 {
+    let h = net.hidden;
+    let (probs, hidden, sums) = forward(net, sample);
+    let mut hidden_grad = vec![0.; h];
+
+    for ((slots, p), t) in sample.slots.iter().zip(&probs).zip(&sample.targets) {
+        let logit_grad = p - t;
+        for &slot in slots {
+            grads.move_bias[slot] += logit_grad;
+            let row = slot * h..(slot + 1) * h;
+            for (g, x) in grads.move_weights[row.clone()].iter_mut().zip(&hidden) {
+                *g += logit_grad * x;
+            }
+            for (hg, w) in hidden_grad.iter_mut().zip(&net.move_weights[row]) {
+                *hg += logit_grad * w;
+            }
+        }
+    }
+
+    for (hg, &x) in hidden_grad.iter_mut().zip(&sums) {
+        *hg *= activation_gradient(x);
+    }
+    for (g, hg) in grads.ft_bias.iter_mut().zip(&hidden_grad) {
+        *g += hg;
+    }
+    for &feat in &sample.features {
+        for (g, hg) in grads.ft_weights[feat * h..(feat + 1) * h].iter_mut().zip(&hidden_grad) {
+            *g += hg;
+        }
+    }
+
+    cross_entropy(&sample.targets, &probs)
+}
+
+struct Adam {
+    m: Vec<f32>,
+    v: Vec<f32>,
+    step: usize,
+}
+
+impl Adam {
+    const BETA1: f32 = 0.9;
+    const BETA2: f32 = 0.999;
+
+    fn new(size: usize) -> Self {
+        Self {
+            m: vec![0.; size],
+            v: vec![0.; size],
+            step: 0,
+        }
+    }
+
+    fn update(&mut self, params: &mut [f32], grads: &[f32], lr: f32) {
+        self.step += 1;
+        let correction1 = 1. - Self::BETA1.powi(self.step as i32);
+        let correction2 = 1. - Self::BETA2.powi(self.step as i32);
+        for (((p, &g), m), v) in params.iter_mut().zip(grads).zip(&mut self.m).zip(&mut self.v) {
+            *m = Self::BETA1 * *m + (1. - Self::BETA1) * g;
+            *v = Self::BETA2 * *v + (1. - Self::BETA2) * g * g;
+            *p -= lr * (*m / correction1) / ((*v / correction2).sqrt() + 1e-8);
+        }
+    }
+}
+
+fn next_rand(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Uniform in [-scale, scale]
+fn rand_weight(state: &mut u64, scale: f32) -> f32 {
+    (next_rand(state) >> 40) as f32 / (1u64 << 24) as f32 * 2. * scale - scale
+}
+
+fn shuffle(order: &mut [usize], rng: &mut u64) {
+    for i in (1..order.len()).rev() {
+        order.swap(i, next_rand(rng) as usize % (i + 1));
+    }
+}
+
+fn random_network(hidden: usize, rng: &mut u64) -> PolicyNetwork {
+    let mut net = PolicyNetwork::zeroed(hidden);
+    // A position has at most 32 active inputs, so this keeps hidden sums in the range the activation is useful in
+    net.ft_weights.iter_mut().for_each(|w| *w = rand_weight(rng, 0.1));
+    net.ft_bias.iter_mut().for_each(|b| *b = 0.5);
+    let scale = 1. / (hidden as f32).sqrt();
+    net.move_weights.iter_mut().for_each(|w| *w = rand_weight(rng, scale));
+    net
+}
+
+#[cfg(test)]
+mod policy_tests {
+    use super::{backward, loss, random_network, Sample};
+    use imm_cee_tee_ess::{board::Board, policy::network::PolicyNetwork};
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        let board = Board::from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R b KQkq - 0 1");
+        let moves = board.legal_moves();
+        let visits = moves
+            .iter()
+            .enumerate()
+            .map(|(i, &m)| (m, (i * 37 % 11) as i32))
+            .collect::<Vec<_>>();
+        let sample = Sample::new(&board, &visits).unwrap();
+
+        let mut rng = 3;
+        let net = random_network(4, &mut rng);
+        let mut grads = PolicyNetwork::zeroed(4);
+        backward(&net, &sample, &mut grads);
+
+        let params = net.to_floats();
+        let grads = grads.to_floats();
+        let mut checked = 0;
+        for (i, &analytic) in grads.iter().enumerate().filter(|(_, g)| g.abs() > 1e-4) {
+            let eps = 1e-2;
+            let mut plus = params.clone();
+            plus[i] += eps;
+            let mut minus = params.clone();
+            minus[i] -= eps;
+            let numeric = (loss(&PolicyNetwork::from_floats(4, &plus), &sample)
+                - loss(&PolicyNetwork::from_floats(4, &minus), &sample))
+                / (2. * eps);
+            assert!(
+                (numeric - analytic).abs() < 1e-2 + 0.05 * analytic.abs(),
+                "{i}: {numeric} vs {analytic}"
+            );
+            checked += 1;
+        }
+        assert!(checked > 50);
+    }
+}
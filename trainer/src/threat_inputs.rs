@@ -1,9 +1,10 @@
-use bullet::{
-    format::{chess::BoardIter, ChessBoard},
-    inputs::InputType,
-};
+use bullet::{format::ChessBoard, inputs::InputType};
 use imm_cee_tee_ess::{
     board::Board,
+    eval::{
+        features::{feature_pairs, FeaturePairs},
+        INPUT_LAYOUT,
+    },
     types::{bitboard::Bitboard, pieces::Color, square::Square},
 };
 
@@ -12,39 +13,22 @@ pub struct ThreatInput;
 
 impl InputType for ThreatInput {
     type RequiredDataType = ChessBoard;
-    type FeatureIter = ThreatIter;
+    type FeatureIter = FeaturePairs;
 
     fn max_active_inputs(&self) -> usize {
-        32
+        INPUT_LAYOUT.threats.max_active()
     }
 
     fn inputs(&self) -> usize {
-        768 * 4
+        INPUT_LAYOUT.threats.bucket_inputs()
     }
 
     fn buckets(&self) -> usize {
-        1
+        INPUT_LAYOUT.bucket_count()
     }
 
     fn feature_iter(&self, pos: &Self::RequiredDataType) -> Self::FeatureIter {
-        let mut pieces = [Bitboard::EMPTY; 6];
-        let mut colors = [Bitboard::EMPTY; 2];
-        for (piece, sq) in pos.into_iter() {
-            let sq = Square(sq);
-            let c = usize::from(piece & 8 > 0);
-            let pc = usize::from(piece & 7);
-            pieces[pc] |= sq.bitboard();
-            colors[c] |= sq.bitboard();
-        }
-        // Bulletformat is always stm relative, so white is stm
-        let board = Board::from_bbs(pieces, colors, Color::White);
-        let threats = board.threats(Color::Black);
-        let defenders = board.threats(Color::White);
-        ThreatIter {
-            board_iter: pos.into_iter(),
-            threats,
-            defenders,
-        }
+        feature_pairs(&to_board(pos), &INPUT_LAYOUT)
     }
 
     fn size(&self) -> usize {
@@ -52,34 +36,65 @@ impl InputType for ThreatInput {
     }
 }
 
-pub struct ThreatIter {
-    board_iter: BoardIter,
-    pub threats: Bitboard,
-    pub defenders: Bitboard,
+/// Builds the engine's board for a training position. Bulletformat is always stm relative, so white is stm.
+pub fn to_board(pos: &ChessBoard) -> Board {
+    let mut pieces = [Bitboard::EMPTY; 6];
+    let mut colors = [Bitboard::EMPTY; 2];
+    for (piece, sq) in pos.into_iter() {
+        let sq = Square(sq);
+        let c = usize::from(piece & 8 > 0);
+        let pc = usize::from(piece & 7);
+        pieces[pc] |= sq.bitboard();
+        colors[c] |= sq.bitboard();
+    }
+    Board::from_bbs(pieces, colors, Color::White)
 }
 
-impl Iterator for ThreatIter {
-    type Item = (usize, usize);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.board_iter.next().map(|(piece, square)| {
-            let c = usize::from(piece & 8 > 0);
-            let p = usize::from(piece & 7);
-            let sq = usize::from(square);
+#[cfg(test)]
+mod threat_input_tests {
+    use super::ThreatInput;
+    use bullet::{format::ChessBoard, inputs::InputType};
+    use imm_cee_tee_ess::{
+        board::Board,
+        eval::{features::board_features, INPUT_LAYOUT},
+    };
+    use std::str::FromStr;
 
-            let map_feature = |feat, threats: Bitboard, defenders: Bitboard| {
-                2 * 768 * usize::from(defenders.contains(sq.into()))
-                    + 768 * usize::from(threats.contains(sq.into()))
-                    + feat
-            };
+    /// The trainer must see exactly the features the engine evaluates, from both sides
+    #[test]
+    fn matches_engine_features() {
+        let mut count = 0;
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "2kr3r/ppp2ppp/2n1bn2/2bqp3/8/2NP1NP1/PPP1PPBP/R1BQ1RK1 b - - 0 9",
+        ] {
+            for offset in 0..5 {
+                let mut board = Board::from_fen(fen);
+                for ply in 0..60 {
+                    let moves = board.legal_moves();
+                    if moves.is_empty() {
+                        break;
+                    }
+                    board.make_move(moves[(ply * 7 + offset * 3) % moves.len()]);
 
-            let stm_feat = [0, 384][c] + 64 * p + sq;
-            let xstm_feat = [384, 0][c] + 64 * p + (sq ^ 56);
+                    let fen = board.to_fen();
+                    let pos = ChessBoard::from_str(&format!("{fen} | 0 | 0.5")).unwrap();
+                    let (mut stm, mut nstm): (Vec<_>, Vec<_>) = ThreatInput.feature_iter(&pos).unzip();
+                    stm.sort_unstable();
+                    nstm.sort_unstable();
 
-            (
-                map_feature(stm_feat, self.threats, self.defenders),
-                map_feature(xstm_feat, self.defenders, self.threats),
-            )
-        })
+                    let engine = board_features(&board, &INPUT_LAYOUT);
+                    assert_eq!(stm, engine[board.stm()].to_vec(), "stm features of {fen}");
+                    assert_eq!(nstm, engine[!board.stm()].to_vec(), "nstm features of {fen}");
+                    count += 1;
+                }
+            }
+        }
+        assert!(count > 1000);
     }
 }
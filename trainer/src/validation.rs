@@ -0,0 +1,170 @@
+use crate::{
+    config::Config,
+    data::{position_count, read_positions},
+    threat_inputs::to_board,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+};
+
+/// Header of the CSV file validation results are appended to
+const CSV_HEADER: &str = "superbatch,positions,loss,result_loss,eval_loss,sign_accuracy";
+
+/// A position set aside to measure how well the network generalizes
+struct Sample {
+    fen: String,
+    /// Search score of the position in centipawns, for the side to move
+    score: f32,
+    /// Game result for the side to move: 0, 0.5, or 1
+    result: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Metrics {
+    /// Loss against the same blend of result and score the network is trained on
+    pub loss: f32,
+    /// Loss against the game result alone
+    pub result_loss: f32,
+    /// Loss against the search score alone
+    pub eval_loss: f32,
+    /// How often the eval has the sign of the result, over decisive games
+    pub sign_accuracy: f32,
+}
+
+pub struct Validation {
+    samples: Vec<Sample>,
+    csv: File,
+    eval_scale: f32,
+}
+
+impl Validation {
+    /// Gathers the validation positions of a run, or returns `None` when it has no validation set
+    pub fn new(config: &Config, eval_scale: f32) -> Option<Self> {
+        let mut positions = Vec::new();
+        if let Some(path) = &config.validation {
+            positions.extend(read_positions(path, 0, config.validation_positions));
+        }
+        if config.holdout > 0 {
+            // Share what's left of the budget between the held out ends of every file
+            let per_file = config.validation_positions.saturating_sub(positions.len()) / config.data.len();
+            for file in &config.data {
+                let start = position_count(&file.path).saturating_sub(config.holdout as u64);
+                positions.extend(read_positions(&file.path, start, per_file.min(config.holdout)));
+            }
+        }
+        if positions.is_empty() {
+            return None;
+        }
+
+        let samples = positions
+            .iter()
+            .map(|pos| Sample {
+                fen: to_board(pos).to_fen(),
+                score: f32::from(pos.score()),
+                result: pos.result(),
+            })
+            .collect();
+
+        let path = format!("{}/{}-validation.csv", config.output, config.net_id);
+        std::fs::create_dir_all(&config.output).unwrap();
+        let fresh = !std::path::Path::new(&path).exists();
+        let mut csv = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap_or_else(|err| panic!("Could not open {path}: {err}"));
+        if fresh {
+            writeln!(csv, "{CSV_HEADER}").unwrap();
+        }
+        Some(Self {
+            samples,
+            csv,
+            eval_scale,
+        })
+    }
+
+    /// Measures the network through `eval`, which gives the raw output for a fen in centipawns, and logs the result.
+    /// `blend` is how much of the training target is the game result at this point of the run.
+    pub fn run(&mut self, superbatch: usize, blend: f32, eval: impl Fn(&str) -> f32) -> Metrics {
+        let outcomes = self
+            .samples
+            .iter()
+            .map(|s| (eval(&s.fen), s.score, s.result))
+            .collect::<Vec<_>>();
+        let m = metrics(&outcomes, blend, self.eval_scale);
+
+        println!(
+            "Validation at superbatch {superbatch}: loss {:.6}, result loss {:.6}, eval loss {:.6}, sign accuracy {:.2}%",
+            m.loss,
+            m.result_loss,
+            m.eval_loss,
+            100. * m.sign_accuracy
+        );
+        writeln!(
+            self.csv,
+            "{superbatch},{},{},{},{},{}",
+            outcomes.len(),
+            m.loss,
+            m.result_loss,
+            m.eval_loss,
+            m.sign_accuracy
+        )
+        .unwrap();
+        m
+    }
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1. / (1. + (-x).exp())
+}
+
+/// Computes the metrics of (network eval, search score, result) triples, with the same sigmoid and mean squared
+/// error the trainer uses
+fn metrics(outcomes: &[(f32, f32, f32)], blend: f32, eval_scale: f32) -> Metrics {
+    let mut loss = 0.;
+    let mut result_loss = 0.;
+    let mut eval_loss = 0.;
+    let mut decisive = 0;
+    let mut correct = 0;
+
+    for &(eval, score, result) in outcomes {
+        let predicted = sigmoid(eval / eval_scale);
+        let score = sigmoid(score / eval_scale);
+        let target = blend * result + (1. - blend) * score;
+        loss += (predicted - target).powi(2);
+        result_loss += (predicted - result).powi(2);
+        eval_loss += (predicted - score).powi(2);
+
+        if result != 0.5 {
+            decisive += 1;
+            correct += usize::from((eval > 0.) == (result > 0.5));
+        }
+    }
+
+    let n = outcomes.len().max(1) as f32;
+    Metrics {
+        loss: loss / n,
+        result_loss: result_loss / n,
+        eval_loss: eval_loss / n,
+        sign_accuracy: correct as f32 / decisive.max(1) as f32,
+    }
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use super::metrics;
+
+    #[test]
+    fn perfect_and_wrong_predictions() {
+        // An eval that matches the score exactly has no eval loss
+        let m = metrics(&[(100., 100., 1.), (-300., -300., 0.), (0., 0., 0.5)], 0., 400.);
+        assert!(m.loss < 1e-6 && m.eval_loss < 1e-6);
+        assert!((m.sign_accuracy - 1.).abs() < 1e-6);
+
+        let m = metrics(&[(400., 400., 0.), (-400., -400., 1.)], 1., 400.);
+        assert!(m.sign_accuracy.abs() < 1e-6);
+        assert!((m.loss - m.result_loss).abs() < 1e-6);
+        assert!(m.result_loss > 0.5);
+    }
+}